@@ -1,6 +1,4 @@
 use std::collections::HashMap;
-use itertools::Itertools;
-use regex::Regex;
 use once_cell::sync::Lazy;
 use crate::types::*;
 use crate::malerr;
@@ -15,36 +13,89 @@ static NAME2SYMBOL: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
         ("@", "deref"),
     ].iter().cloned().collect()});
 
-static RE: Lazy<Regex> = Lazy::new(||
-    Regex::new(r#"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]*)"#).unwrap());
-
+// byte-oriented lexer yielding tokens borrowed from the source text.
+// Comments are returned as tokens (read_form turns them into Comment).
 #[derive(Debug, Clone)]
-pub struct Reader {
-    tokens: Vec<Token>,
+struct Lexer<'a> {
+    src: &'a str,
     pos: usize,
+    line: usize,
+    col: usize,
 }
 
-impl Reader {
-    fn new(s: &[Token]) -> Self {
-        Self { tokens: s.to_vec(), pos: 0, }
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Self { src, pos: 0, line: 1, col: 1, }
     }
 
-    // just returns the token at the current position.
-    fn peek(&self) -> Option<Token> {
-        self.tokens.get(self.pos).cloned()
-    }
+    fn byte(&self, i: usize) -> Option<u8> { self.src.as_bytes().get(i).copied() }
 
-    // returns the token at the current position and increments the position
-    fn next(&mut self) -> Option<Token> {
+    fn bump(&mut self) {
+        let b = self.src.as_bytes()[self.pos];
         self.pos += 1;
-        self.tokens.get(self.pos - 1).cloned()
+        if b == b'\n' {
+            self.line += 1;
+            self.col = 1;
+        } else if b & 0xc0 != 0x80 {    // skip utf-8 continuation bytes
+            self.col += 1;
+        }
+    }
+
+    fn bump_while(&mut self, f: impl Fn(u8) -> bool) {
+        while self.byte(self.pos).map_or(false, &f) { self.bump(); }
+    }
+}
+
+fn is_blank(b: u8) -> bool { b.is_ascii_whitespace() || b == b',' || b == 0x0b }
+
+fn is_delim(b: u8) -> bool { is_blank(b) || b"[]{}()'\"`;".contains(&b) }
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        self.bump_while(is_blank);
+        let (start, line, col) = (self.pos, self.line, self.col);
+        match self.byte(start)? {
+            b'~' if self.byte(start + 1) == Some(b'@') => { self.bump(); self.bump(); },
+            b'[' | b']' | b'{' | b'}' | b'(' | b')' | b'\'' | b'`' | b'~' | b'^' | b'@' =>
+                self.bump(),
+            b'"' => {
+                self.bump();
+                while let Some(b) = self.byte(self.pos) {
+                    self.bump();
+                    if b == b'"' { break; }
+                    if b == b'\\' && self.pos < self.src.len() { self.bump(); }
+                }
+            },
+            b';' => self.bump_while(|b| b != b'\n'),
+            _ => self.bump_while(|b| !is_delim(b)),
+        }
+        Some(Token::new(&self.src[start..self.pos], line, col))
     }
 }
 
-// take a single string and return an array/list of all the tokens (strings) in it.
-fn tokenize(s: &str) -> Vec<Token> {
-    RE.captures_iter(s.trim())
-        .map(|cap| Token::new(&cap[1])).collect_vec()
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<Token<'a>>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(s: &'a str) -> Self {
+        Self { lexer: Lexer::new(s), peeked: None, }
+    }
+
+    // just returns the token at the current position.
+    fn peek(&mut self) -> Option<Token<'a>> {
+        if self.peeked.is_none() { self.peeked = self.lexer.next(); }
+        self.peeked
+    }
+
+    // returns the token at the current position and advances the lexer
+    fn next(&mut self) -> Option<Token<'a>> {
+        self.peeked.take().or_else(|| self.lexer.next())
+    }
 }
 
 // look at the contents of the token
@@ -116,11 +167,10 @@ pub fn read_form(reader: &mut Reader) -> Result<MalType> {
     }
 }
 
-// call tokenize and then create a new Reader object instance with the tokens.
+// create a new Reader object instance over the source text.
 // Then it will call read_form with the Reader instance.
 pub fn read_str(s: &str) -> Result<MalType> {
-    let tokens = tokenize(s);
-//    eprintln!("{} {:?}", s, tokens);
-    // if tokens.is_empty() { return Err("Blank Line") }
-    read_form(&mut Reader::new(&tokens))
+    let mut reader = Reader::new(s);
+    if reader.peek().is_none() { return Ok(MalType::Comment) }   // blank line
+    read_form(&mut reader)
 }
@@ -5,7 +5,6 @@ use std::hash::{Hash, Hasher};
 use std::collections::HashMap;
 use std::ops::{Add, Sub, Mul, Div};
 use itertools::Itertools;
-use regex::Regex;
 use once_cell::sync::Lazy;
 use crate::env::*;
 
@@ -29,9 +28,6 @@ static MALTYPE2NAME: Lazy<HashMap<&'static MalType, &'static str>> = Lazy::new(|
 static NAME2MALTYPE: Lazy<HashMap<&'static str, &'static MalType>> = Lazy::new(||
     _NAME2MALTYPE.iter().cloned().collect());
 
-static STRING_RE: Lazy<Regex> = Lazy::new(||
-    Regex::new(r#""(?:\\.|[^\\"])*"?"#).unwrap());
-
 pub type Result<T> = std::result::Result<T, MalError>;
 
 #[macro_export]
@@ -43,17 +39,27 @@ macro_rules! malerr {
 
 // ----------- Token -----------
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Token(String);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl<'a> Token<'a> {
+    pub fn new(text: &'a str, line: usize, col: usize) -> Self { Self { text, line, col } }
 
-impl Token {
-    pub fn new(s: &str) -> Self { Self(s.to_string()) }
-    pub fn to_string(&self) -> String { self.0.clone() }
+    // true if the token is a string with an unescaped closing quote
+    pub fn is_closed_str(&self) -> bool {
+        let b = self.text.as_bytes();
+        if b.len() < 2 || b[0] != b'"' || b[b.len() - 1] != b'"' { return false; }
+        b[1..b.len() - 1].iter().rev().take_while(|&&c| c == b'\\').count() % 2 == 0
+    }
 }
 
-impl fmt::Display for Token {
+impl fmt::Display for Token<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{}", self.text)
     }
 }
 
@@ -205,18 +211,18 @@ impl MalType {
     }
 
     pub fn from_token(token: &Token) -> Result<Self> {
-        let s = token.to_string();
+        let s = token.text;
         if let Some(';') = s.chars().next() {
             Ok(Self::Comment)
         } else if let Ok(num) = s.parse::<Number>() {
             Ok(Self::Num(num))
-        } else if STRING_RE.is_match(&s) {
+        } else if token.is_closed_str() {
             Ok(Self::String(Self::_unescape(&s[1..(s.len() - 1)])))
         } else if let Some('\"') = s.chars().next() {
             Err(malerr!("expected '\"', got EOF"))
         } else if let Some(':') = s.chars().next() {
             Ok(Self::Keyword(s[1..].to_string()))
-        } else { match &s as &str {
+        } else { match s {
             x if NAME2MALTYPE.contains_key(x) => Ok(NAME2MALTYPE[x].clone()),
             x => Ok(Self::Symbol(x.to_string())),
         } }
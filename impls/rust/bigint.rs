@@ -0,0 +1,298 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Arbitrary precision integer: sign and magnitude, the magnitude as
+// base 2^32 limbs, least significant first, without trailing zeros
+// (so zero is an empty magnitude and never negative).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    neg: bool,
+    mag: Vec<u32>,
+}
+
+// magnitude helpers
+
+fn trim(mut a: Vec<u32>) -> Vec<u32> {
+    while a.last() == Some(&0) {
+        a.pop();
+    }
+    a
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut res = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0u64;
+    for (i, &x) in a.iter().enumerate() {
+        let sum = x as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        res.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry > 0 {
+        res.push(carry as u32);
+    }
+    res
+}
+
+// requires a >= b
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &x) in a.iter().enumerate() {
+        let mut diff = x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        res.push(diff as u32);
+    }
+    trim(res)
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return vec![];
+    }
+    let mut res = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u64 * y as u64 + res[i + j] as u64 + carry;
+            res[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        res[i + b.len()] = carry as u32;
+    }
+    trim(res)
+}
+
+fn divrem_small(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+    let mut quot = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let cur = (rem << 32) | a[i] as u64;
+        quot[i] = (cur / d as u64) as u32;
+        rem = cur % d as u64;
+    }
+    (trim(quot), rem as u32)
+}
+
+fn bit_len(a: &[u32]) -> usize {
+    match a.last() {
+        Some(top) => a.len() * 32 - top.leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+// binary long division; b must be non-zero
+fn divrem_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_mag(a, b) == Ordering::Less {
+        return (vec![], a.to_vec());
+    }
+    if b.len() == 1 {
+        let (q, r) = divrem_small(a, b[0]);
+        return (q, trim(vec![r]));
+    }
+    let mut quot = vec![0u32; a.len()];
+    let mut rem: Vec<u32> = vec![];
+    for i in (0..bit_len(a)).rev() {
+        // rem = rem * 2 + bit i of a
+        let mut carry = (a[i / 32] >> (i % 32)) & 1;
+        for limb in rem.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if carry > 0 {
+            rem.push(carry);
+        }
+        if cmp_mag(&rem, b) != Ordering::Less {
+            rem = sub_mag(&rem, b);
+            quot[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (trim(quot), rem)
+}
+
+impl BigInt {
+    fn new(neg: bool, mag: Vec<u32>) -> BigInt {
+        let mag = trim(mag);
+        BigInt {
+            neg: neg && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    pub fn from_i64(i: i64) -> BigInt {
+        let u = i.unsigned_abs();
+        BigInt::new(i < 0, vec![u as u32, (u >> 32) as u32])
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let u = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &l| (acc << 32) | l as u64);
+        if self.neg {
+            if u <= i64::MAX as u64 + 1 {
+                Some((u as i64).wrapping_neg())
+            } else {
+                None
+            }
+        } else if u <= i64::MAX as u64 {
+            Some(u as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let f = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0f64, |acc, &l| acc * 4294967296.0 + l as f64);
+        if self.neg {
+            -f
+        } else {
+            f
+        }
+    }
+
+    // optional '-' followed by decimal digits
+    pub fn parse(s: &str) -> Option<BigInt> {
+        let (neg, digits) = match s.strip_prefix('-') {
+            Some(d) => (true, d),
+            None => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut mag: Vec<u32> = vec![];
+        // nine decimal digits at a time
+        for chunk in digits.as_bytes().chunks(9) {
+            let mut scale = 1u64;
+            let mut val = 0u64;
+            for &b in chunk {
+                scale *= 10;
+                val = val * 10 + (b - b'0') as u64;
+            }
+            let mut carry = val;
+            for limb in mag.iter_mut() {
+                let t = *limb as u64 * scale + carry;
+                *limb = t as u32;
+                carry = t >> 32;
+            }
+            if carry > 0 {
+                mag.push(carry as u32);
+            }
+        }
+        Some(BigInt::new(neg, mag))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.neg, self.mag.clone())
+    }
+
+    pub fn is_neg(&self) -> bool {
+        self.neg
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt::new(self.neg, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::new(other.neg, sub_mag(&other.mag, &self.mag)),
+            _ => BigInt::new(self.neg, sub_mag(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::new(self.neg != other.neg, mul_mag(&self.mag, &other.mag))
+    }
+
+    // truncating division and the matching remainder (which has the
+    // sign of the dividend), like i64's / and %
+    pub fn divrem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = divrem_mag(&self.mag, &other.mag);
+        Some((
+            BigInt::new(self.neg != other.neg, q),
+            BigInt::new(self.neg, r),
+        ))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // peel off nine decimal digits at a time
+        let mut chunks = vec![];
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let (q, r) = divrem_small(&mag, 1_000_000_000);
+            chunks.push(r);
+            mag = q;
+        }
+        let mut s = String::new();
+        if self.neg {
+            s.push('-');
+        }
+        s.push_str(&chunks.pop().unwrap_or(0).to_string());
+        for c in chunks.iter().rev() {
+            s.push_str(&format!("{:09}", c));
+        }
+        write!(f, "{}", s)
+    }
+}
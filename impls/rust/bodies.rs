@@ -0,0 +1,48 @@
+use std::rc::{Rc, Weak};
+
+use fnv::FnvHashMap;
+
+use crate::pvec::PVec;
+use crate::types::MalVal;
+use crate::types::MalVal::List;
+
+// What an evaluator derives from a fn* body (analyzed or compiled code),
+// kept for the next call. Entries are keyed by the address of the body's
+// list, which every closure made from the same fn* shares (a top-level
+// fn* too, while resolve_fn can reuse its resolved body); the weak
+// reference keeps that address from being reused while the entry exists.
+pub struct BodyCache<T> {
+    entries: FnvHashMap<usize, (Weak<PVec<MalVal>>, T)>,
+    sweep_at: usize,
+}
+
+impl<T: Clone> BodyCache<T> {
+    pub fn new() -> BodyCache<T> {
+        BodyCache {
+            entries: FnvHashMap::default(),
+            sweep_at: 256,
+        }
+    }
+
+    pub fn get(&self, body: &MalVal) -> Option<T> {
+        match body {
+            List(l, _) => match self.entries.get(&(Rc::as_ptr(l) as usize)) {
+                Some((w, val)) if w.strong_count() > 0 => Some(val.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // bodies other than lists are cheap to redo and are not kept
+    pub fn insert(&mut self, body: &MalVal, val: T) {
+        if let List(l, _) = body {
+            if self.entries.len() >= self.sweep_at {
+                self.entries.retain(|_, (w, _)| w.strong_count() > 0);
+                self.sweep_at = 256.max(2 * self.entries.len());
+            }
+            self.entries
+                .insert(Rc::as_ptr(l) as usize, (Rc::downgrade(l), val));
+        }
+    }
+}
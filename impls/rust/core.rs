@@ -5,24 +5,274 @@ use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
+use regex::Captures;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+use crate::bigint::BigInt;
+use crate::env::expected_args;
 use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
-
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+use crate::pvec::PVec;
+use crate::reader::{read_all, read_source};
+use crate::types::MalErr::{ErrMalVal, ErrTyped};
+use crate::types::MalVal::{
+    Atom, Big, Bool, Float, Func, Hash, Int, Keyword, List, Local, MalFunc, Nil, Regex, Str, Sym,
+    Vector,
+};
+use crate::types::{
+    _assoc, _dissoc, atom, error, ex_parts, hash_map, integer, keyword, regex, sym, typed_error,
+    MalArgs, MalErr, MalRegex, MalRet, MalVal,
+};
+
+// What a builtin takes: a name for errors and which args it accepts.
+#[derive(Clone, Copy)]
+pub struct Kind(&'static str, fn(&MalVal) -> bool);
+
+pub const ANY: Kind = Kind("any value", |_| true);
+pub const NUM: Kind = Kind("number", |v| matches!(v, Int(_) | Big(_) | Float(_)));
+pub const INT: Kind = Kind("int", |v| matches!(v, Int(_)));
+pub const STR: Kind = Kind("string", |v| matches!(v, Str(_)));
+pub const STR_NIL: Kind = Kind("string or nil", |v| matches!(v, Str(_) | Nil));
+pub const NAME: Kind = Kind("string or keyword", |v| matches!(v, Str(_) | Keyword(_)));
+pub const SEQ: Kind = Kind("list or vector", |v| matches!(v, List(..) | Vector(..)));
+pub const SEQ_NIL: Kind = Kind("list, vector or nil", |v| {
+    matches!(v, List(..) | Vector(..) | Nil)
+});
+pub const SEQABLE: Kind = Kind("list, vector, string or nil", |v| {
+    matches!(v, List(..) | Vector(..) | Str(_) | Nil)
+});
+pub const MAP: Kind = Kind("map", |v| matches!(v, Hash(..)));
+pub const MAP_NIL: Kind = Kind("map or nil", |v| matches!(v, Hash(..) | Nil));
+pub const FN: Kind = Kind("function", |v| {
+    matches!(v, Func(..) | MalFunc { .. } | Keyword(_))
+});
+pub const ATOM: Kind = Kind("atom", |v| matches!(v, Atom(_)));
+pub const RE: Kind = Kind("regex", |v| matches!(v, Regex(_)));
+pub const STR_RE: Kind = Kind("string or regex", |v| matches!(v, Str(_) | Regex(_)));
+pub const STR_FN: Kind = Kind("string or function", |v| STR.1(v) || FN.1(v));
+
+// An ns() entry whose args are checked before the function sees them, so
+// that it can index them: the kinds of the args it needs, in a second
+// [...] those of optional ones, and after & the kind of any more.
+//
+//   builtin!("nth", [SEQ, INT], nth)
+//   builtin!("read-string", [STR] [STR_NIL], read_string)
+//   builtin!("list", [] & ANY, |a| Ok(list!(a)))
+macro_rules! builtin {
+    ($name:expr, [$($k:expr),*] $([$($o:expr),*])? $(& $rest:expr)?, $f:expr) => {{
+        ($name, $crate::types::func(|a: $crate::types::MalArgs| {
+            let rest: Option<$crate::core::Kind> = None$(.or(Some($rest)))?;
+            $crate::core::check_args($name, &a, &[$($k),*], &[$($($o),*)?], rest)?;
+            let f: fn($crate::types::MalArgs) -> $crate::types::MalRet = $f;
+            f(a)
+        }))
+    }};
+}
+
+pub fn check_args(
+    name: &str,
+    a: &[MalVal],
+    required: &[Kind],
+    optional: &[Kind],
+    rest: Option<Kind>,
+) -> Result<(), MalErr> {
+    let (min, max) = (required.len(), required.len() + optional.len());
+    if a.len() < min || (a.len() > max && rest.is_none()) {
+        let counts: Vec<_> = match rest {
+            Some(_) => vec![(min, true)],
+            None => (min..=max).map(|n| (n, false)).collect(),
+        };
+        let msg = format!("{}: {}", name, expected_args(&counts, a.len()));
+        return Err(ErrTyped("arity-error", msg));
+    }
+    for (i, v) in a.iter().enumerate() {
+        let kind = match i {
+            i if i < min => required[i],
+            i if i < max => optional[i - min],
+            _ => rest.unwrap_or(ANY),
+        };
+        if !(kind.1)(v) {
+            let msg = format!(
+                "{}: expected {} for arg {}, got {}",
+                name,
+                kind.0,
+                i + 1,
+                type_name(v)
+            );
+            return Err(ErrTyped("type-error", msg));
+        }
+    }
+    Ok(())
+}
+
+fn type_name(v: &MalVal) -> &'static str {
+    match v {
+        Nil => "nil",
+        Bool(_) => "boolean",
+        Int(_) | Big(_) => "int",
+        Float(_) => "float",
+        Str(_) => "string",
+        Sym(_) | Local(..) => "symbol",
+        Keyword(_) => "keyword",
+        Regex(_) => "regex",
+        List(..) => "list",
+        Vector(..) => "vector",
+        Hash(..) => "map",
+        MalFunc { is_macro: true, .. } => "macro",
+        Func(..) | MalFunc { .. } => "function",
+        Atom(_) => "atom",
+    }
+}
+
+// ints use checked i64 arithmetic ($int returns None on overflow) and
+// fall back to bigints; if either arg is a float both are used as floats
+macro_rules! fn_t_num_num {
+    ($int:expr, $big:expr, $float:expr) => {{
+        |x: &MalVal, y: &MalVal| {
+            let fi: fn(i64, i64) -> Option<MalVal> = $int;
+            let fb: fn(&BigInt, &BigInt) -> MalRet = $big;
+            let ff: fn(f64, f64) -> MalVal = $float;
+            match (x, y) {
+                (Int(a0), Int(a1)) => match fi(*a0, *a1) {
+                    Some(r) => Ok(r),
+                    None => fb(&BigInt::from_i64(*a0), &BigInt::from_i64(*a1)),
+                },
+                (Float(_), _) | (_, Float(_)) => match (x.to_f64(), y.to_f64()) {
+                    (Some(x), Some(y)) => Ok(ff(x, y)),
+                    _ => typed_error("type-error", "expecting (number,number) args"),
+                },
+                _ => match (x.to_bigint(), y.to_bigint()) {
+                    (Some(x), Some(y)) => fb(&x, &y),
+                    _ => typed_error("type-error", "expecting (number,number) args"),
+                },
+            }
         }
     }};
 }
 
+type NumOp = fn(&MalVal, &MalVal) -> MalRet;
+
+const ADD: NumOp = fn_t_num_num!(
+    |i, j| i.checked_add(j).map(Int),
+    |x, y| Ok(integer(x.add(y))),
+    |x, y| Float(x + y)
+);
+const SUB: NumOp = fn_t_num_num!(
+    |i, j| i.checked_sub(j).map(Int),
+    |x, y| Ok(integer(x.sub(y))),
+    |x, y| Float(x - y)
+);
+const MUL: NumOp = fn_t_num_num!(
+    |i, j| i.checked_mul(j).map(Int),
+    |x, y| Ok(integer(x.mul(y))),
+    |x, y| Float(x * y)
+);
+const DIV: NumOp = fn_t_num_num!(
+    |i, j| i.checked_div(j).map(Int),
+    |x, y| match x.divrem(y) {
+        Some((q, _)) => Ok(integer(q)),
+        None => typed_error("arithmetic-error", "division by zero"),
+    },
+    |x, y| Float(x / y)
+);
+// quot and rem truncate, mod floors: its result has the divisor's sign
+const QUOT: NumOp = fn_t_num_num!(
+    |i, j| i.checked_div(j).map(Int),
+    |x, y| match x.divrem(y) {
+        Some((q, _)) => Ok(integer(q)),
+        None => typed_error("arithmetic-error", "division by zero"),
+    },
+    |x, y| Float((x / y).trunc())
+);
+const REM: NumOp = fn_t_num_num!(
+    |i, j| i.checked_rem(j).map(Int),
+    |x, y| match x.divrem(y) {
+        Some((_, r)) => Ok(integer(r)),
+        None => typed_error("arithmetic-error", "division by zero"),
+    },
+    |x, y| Float(x % y)
+);
+const MOD: NumOp = fn_t_num_num!(
+    |i, j| match i.checked_rem(j) {
+        Some(r) if r != 0 && (r < 0) != (j < 0) => Some(Int(r + j)),
+        r => r.map(Int),
+    },
+    |x, y| match x.divrem(y) {
+        Some((_, r)) if !r.is_zero() && r.is_neg() != y.is_neg() => Ok(integer(r.add(y))),
+        Some((_, r)) => Ok(integer(r)),
+        None => typed_error("arithmetic-error", "division by zero"),
+    },
+    |x, y| match x % y {
+        r if r != 0.0 && (r < 0.0) != (y < 0.0) => Float(r + y),
+        r => Float(r),
+    }
+);
+const LT: NumOp = fn_t_num_num!(|i, j| Some(Bool(i < j)), |x, y| Ok(Bool(x < y)), |x, y| {
+    Bool(x < y)
+});
+const LE: NumOp = fn_t_num_num!(
+    |i, j| Some(Bool(i <= j)),
+    |x, y| Ok(Bool(x <= y)),
+    |x, y| Bool(x <= y)
+);
+const GT: NumOp = fn_t_num_num!(|i, j| Some(Bool(i > j)), |x, y| Ok(Bool(x > y)), |x, y| {
+    Bool(x > y)
+});
+const GE: NumOp = fn_t_num_num!(
+    |i, j| Some(Bool(i >= j)),
+    |x, y| Ok(Bool(x >= y)),
+    |x, y| Bool(x >= y)
+);
+
+// (+ a b c) is (+ (+ a b) c), and (- a) is (- 0 a): the op on the unit
+fn fold(a: &[MalVal], unit: MalVal, op: NumOp) -> MalRet {
+    match a {
+        [] => Ok(unit),
+        [x] => op(&unit, x),
+        [x, rest @ ..] => rest.iter().try_fold(x.clone(), |acc, y| op(&acc, y)),
+    }
+}
+
+// (< a b c) holds if each arg is less than the next
+fn chain(a: &[MalVal], op: NumOp) -> MalRet {
+    for w in a.windows(2) {
+        if let Bool(false) = op(&w[0], &w[1])? {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
+// the first arg that op holds for against all the others, as it was given
+fn extreme(a: &[MalVal], op: NumOp) -> MalRet {
+    let mut best = &a[0];
+    for x in &a[1..] {
+        if let Bool(true) = op(x, best)? {
+            best = x;
+        }
+    }
+    Ok(best.clone())
+}
+
+// quot, rem and mod have no answer for a zero divisor, even a float one
+fn by_nonzero(a: MalArgs, op: NumOp) -> MalRet {
+    match a[1] {
+        Float(0.0) => typed_error("arithmetic-error", "division by zero"),
+        _ => op(&a[0], &a[1]),
+    }
+}
+
+fn abs(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) => Ok(i
+            .checked_abs()
+            .map_or_else(|| integer(BigInt::from_i64(i).abs()), Int)),
+        Big(ref b) => Ok(integer(b.abs())),
+        Float(f) => Ok(Float(f.abs())),
+        _ => typed_error("type-error", "abs: expecting number arg"),
+    }
+}
+
 macro_rules! fn_is_type {
   ($($ps:pat),*) => {{
     |a:MalArgs| { Ok(Bool(match a[0] { $($ps => true,)* _ => false})) }
@@ -39,15 +289,32 @@ macro_rules! fn_str {
     ($fn:expr) => {{
         |a: MalArgs| match a[0].clone() {
             Str(a0) => $fn(a0),
-            _ => error("expecting (str) arg"),
+            _ => typed_error("type-error", "expecting (str) arg"),
         }
     }};
 }
 
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
-        _ => error("illegal symbol call"),
+        Str(ref s) => Ok(sym(s)),
+        _ => typed_error("type-error", "illegal symbol call"),
+    }
+}
+
+// optional second argument names the source for form locations
+fn read_string(a: MalArgs) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (Str(s), None) | (Str(s), Some(Nil)) => read_source(s, None),
+        (Str(s), Some(Str(f))) => read_source(s, Some(f)),
+        _ => typed_error("type-error", "read-string: expecting (str [str]) args"),
+    }
+}
+
+fn read_string_all(a: MalArgs) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (Str(s), None) | (Str(s), Some(Nil)) => Ok(list!(read_all(s, None)?)),
+        (Str(s), Some(Str(f))) => Ok(list!(read_all(s, Some(f))?)),
+        _ => typed_error("type-error", "read-string-all: expecting (str [str]) args"),
     }
 }
 
@@ -72,10 +339,10 @@ fn readline(a: MalArgs) -> MalRet {
                     Ok(Str(line))
                 }
                 Err(ReadlineError::Eof) => Ok(Nil),
-                Err(e) => error(&format!("{:?}", e)),
+                Err(e) => typed_error("io-error", &format!("{:?}", e)),
             }
         }
-        _ => error("readline: prompt is not Str"),
+        _ => typed_error("type-error", "readline: prompt is not Str"),
     }
 }
 
@@ -83,7 +350,7 @@ fn slurp(f: String) -> MalRet {
     let mut s = String::new();
     match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
         Ok(_) => Ok(Str(s)),
-        Err(e) => error(&e.to_string()),
+        Err(e) => typed_error("io-error", &e.to_string()),
     }
 }
 
@@ -97,90 +364,109 @@ fn time_ms(_a: MalArgs) -> MalRet {
     ))
 }
 
+fn int(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) => Ok(Int(i)),
+        // `as` saturates, so check the range (-2^63 <= f < 2^63) first
+        Float(f) if f >= i64::MIN as f64 && f < -(i64::MIN as f64) => Ok(Int(f as i64)),
+        Float(_) | Big(_) => typed_error("arithmetic-error", "int: value out of range"),
+        _ => typed_error("type-error", "int: expecting number arg"),
+    }
+}
+
+fn double(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(_) | Big(_) | Float(_) => Ok(Float(a[0].to_f64().unwrap_or(0.0))),
+        _ => typed_error("type-error", "double: expecting number arg"),
+    }
+}
+
 fn get(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+    match a[0] {
+        Nil => Ok(Nil),
+        Hash(ref hm, _) => match hm.get(&a[1]) {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
-        _ => error("illegal get args"),
+        _ => typed_error("type-error", "illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+        _ => typed_error("type-error", "assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("dissoc on non-Hash Map"),
+        _ => typed_error("type-error", "dissoc on non-Hash Map"),
     }
 }
 
 fn contains_q(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
-        _ => error("illegal get args"),
+    match a[0] {
+        Hash(ref hm, _) => Ok(Bool(hm.contains_key(&a[1]))),
+        _ => typed_error("type-error", "illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect())),
+        _ => typed_error("type-error", "keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
-        _ => error("keys requires Hash Map"),
+        _ => typed_error("type-error", "keys requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
-        _ => error("non-seq passed to vec"),
+        List(ref v, _) | Vector(ref v, _) => Ok(Vector(v.clone(), Rc::new(Nil))),
+        _ => typed_error("type-error", "non-seq passed to vec"),
     }
 }
 
 fn cons(a: MalArgs) -> MalRet {
     match a[1].clone() {
         List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
+            let mut new_v = (*v).clone();
+            new_v.push_front(a[0].clone());
+            Ok(List(Rc::new(new_v), Rc::new(Nil)))
         }
-        _ => error("cons expects seq as second arg"),
+        _ => typed_error("type-error", "cons expects seq as second arg"),
     }
 }
 
+// the first non-empty seq is shared rather than copied
 fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
+    let mut new_v = PVec::new();
     for seq in a.iter() {
         match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+            List(v, _) | Vector(v, _) if new_v.is_empty() => new_v = (**v).clone(),
+            List(v, _) | Vector(v, _) => new_v.extend(v.iter().cloned()),
+            _ => return typed_error("type-error", "non-seq passed to concat"),
         }
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(List(Rc::new(new_v), Rc::new(Nil)))
 }
 
 fn nth(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
             if seq.len() <= idx as usize {
-                return error("nth: index out of range");
+                return typed_error("index-out-of-bounds", "nth: index out of range");
             }
             Ok(seq[idx as usize].clone())
         }
-        _ => error("invalid args to nth"),
+        _ => typed_error("type-error", "invalid args to nth"),
     }
 }
 
@@ -189,21 +475,15 @@ fn first(a: MalArgs) -> MalRet {
         List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
         List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
         Nil => Ok(Nil),
-        _ => error("invalid args to first"),
+        _ => typed_error("type-error", "invalid args to first"),
     }
 }
 
 fn rest(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
-            }
-        }
+        List(ref seq, _) | Vector(ref seq, _) => Ok(List(Rc::new(seq.slice(1)), Rc::new(Nil))),
         Nil => Ok(list![]),
-        _ => error("invalid args to first"),
+        _ => typed_error("type-error", "invalid args to first"),
     }
 }
 
@@ -212,10 +492,16 @@ fn apply(a: MalArgs) -> MalRet {
         List(ref v, _) | Vector(ref v, _) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend(v.iter().cloned());
             f.apply(fargs)
         }
-        _ => error("apply called with non-seq"),
+        ref v => typed_error(
+            "type-error",
+            &format!(
+                "apply: expected list or vector for last arg, got {}",
+                type_name(v)
+            ),
+        ),
     }
 }
 
@@ -228,124 +514,463 @@ fn map(a: MalArgs) -> MalRet {
             }
             Ok(list!(res))
         }
-        _ => error("map called with non-seq"),
+        _ => typed_error("type-error", "map called with non-seq"),
     }
 }
 
 fn conj(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
+            let mut new_v = (**v).clone();
+            for x in &a[1..] {
+                new_v.push_front(x.clone());
+            }
+            Ok(List(Rc::new(new_v), Rc::new(Nil)))
+        }
+        Vector(ref v, _) => {
+            let mut new_v = (**v).clone();
+            new_v.extend(a[1..].iter().cloned());
+            Ok(Vector(Rc::new(new_v), Rc::new(Nil)))
         }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
-        _ => error("conj: called with non-seq"),
+        _ => typed_error("type-error", "conj: called with non-seq"),
     }
 }
 
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
         List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
+        List(ref v, _) | Vector(ref v, _) => Ok(List(v.clone(), Rc::new(Nil))),
         Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
-        }
+        Str(ref s) => Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect())),
         Nil => Ok(Nil),
-        _ => error("seq: called with non-seq"),
+        _ => typed_error("type-error", "seq: called with non-seq"),
+    }
+}
+
+// Strings are indexed by char (Unicode scalar value), as seq splits them.
+
+// the byte offset of char i of s, if s has that many chars
+fn char_offset(s: &str, i: i64) -> Option<usize> {
+    match i {
+        i if i < 0 => None,
+        i => s
+            .char_indices()
+            .map(|(b, _)| b)
+            .chain(Some(s.len()))
+            .nth(i as usize),
+    }
+}
+
+// (subs s start [end])
+fn subs(a: MalArgs) -> MalRet {
+    let s = match a[0] {
+        Str(ref s) => s,
+        _ => return typed_error("type-error", "subs: expecting string arg"),
+    };
+    let offset = |i: &MalVal| match i {
+        Int(i) => char_offset(s, *i),
+        _ => None,
+    };
+    let start = offset(&a[1]);
+    let end = a.get(2).map_or(Some(s.len()), offset);
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => Ok(Str(s[start..end].to_string())),
+        _ => typed_error("index-out-of-bounds", "subs: index out of range"),
+    }
+}
+
+// (string/split s sep): the strings between the seps (strings or regexes
+// matching them), without empty ones at the end (unless s is empty); an
+// empty sep splits s into its chars
+fn split(a: MalArgs) -> MalRet {
+    let parts: Vec<MalVal> = match (&a[0], &a[1]) {
+        (Str(s), _) if s.is_empty() => return Ok(vector!(vec![Str(String::new())])),
+        (Str(s), Str(sep)) if sep.is_empty() => s.chars().map(|c| Str(c.to_string())).collect(),
+        (Str(s), Str(sep)) => s.split(sep.as_str()).map(|p| Str(p.to_string())).collect(),
+        (Str(s), Regex(re)) => {
+            // nor is there an empty one first for an empty match there
+            let skip = re.find(s).map_or(0, |m| (m.end() == 0) as usize);
+            re.split(s).skip(skip).map(|p| Str(p.to_string())).collect()
+        }
+        _ => return typed_error("type-error", "string/split: expecting (str str) args"),
+    };
+    let n = parts.len()
+        - parts
+            .iter()
+            .rev()
+            .take_while(|p| **p == Str(String::new()))
+            .count();
+    Ok(vector!(parts[..n].to_vec()))
+}
+
+// (string/join [sep] coll): the items of coll as str prints them
+fn join(a: MalArgs) -> MalRet {
+    let (sep, coll) = match (&a[0], a.get(1)) {
+        (coll, None) => ("", coll),
+        (Str(sep), Some(coll)) => (sep.as_str(), coll),
+        _ => return typed_error("type-error", "string/join: expecting ([str] seq) args"),
+    };
+    match coll {
+        List(v, _) | Vector(v, _) => Ok(Str(pr_seq(v.iter(), false, "", "", sep))),
+        Nil => Ok(Str(String::new())),
+        _ => typed_error("type-error", "string/join: expecting ([str] seq) args"),
+    }
+}
+
+// (index-of s value [from]): the char index value is found at, or nil
+fn index_of(a: MalArgs) -> MalRet {
+    let (s, value) = match (&a[0], &a[1]) {
+        (Str(s), Str(value)) => (s, value),
+        _ => return typed_error("type-error", "index-of: expecting (str str [int]) args"),
+    };
+    let from = match a.get(2) {
+        None => 0,
+        Some(Int(i)) => match char_offset(s, *i) {
+            Some(b) => b,
+            None => return Ok(Nil),
+        },
+        Some(_) => return typed_error("type-error", "index-of: expecting (str str [int]) args"),
+    };
+    match s[from..].find(value.as_str()) {
+        Some(b) => Ok(Int(s[..from + b].chars().count() as i64)),
+        None => Ok(Nil),
+    }
+}
+
+// (replace s match replacement): a string match is replaced as it is; a
+// regex's matches by a string that can refer to its groups as $1. Either
+// may instead be replaced by what a function returns for each match: the
+// string, or for a regex what re-find would give
+fn replace(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1], &a[2]) {
+        (Str(s), Str(from), Str(to)) => Ok(Str(s.replace(from.as_str(), to))),
+        (Str(s), Regex(re), Str(to)) => Ok(Str(re.replace_all(s, to.as_str()).into_owned())),
+        (Str(s), Str(from), f) => {
+            let matches = s.match_indices(from.as_str());
+            replace_each(
+                s,
+                matches.map(|(i, m)| (i, i + m.len(), Str(m.to_string()))),
+                f,
+            )
+        }
+        (Str(s), Regex(re), f) => {
+            let matches = re.captures_iter(s).map(|caps| {
+                let m = caps.get(0).unwrap();
+                (m.start(), m.end(), found(&caps))
+            });
+            replace_each(s, matches, f)
+        }
+        _ => typed_error(
+            "type-error",
+            "replace: expecting (str str|regex str|fn) args",
+        ),
+    }
+}
+
+// s with each match, given as its span and what f is called with,
+// replaced by f's result
+fn replace_each(
+    s: &str,
+    matches: impl Iterator<Item = (usize, usize, MalVal)>,
+    f: &MalVal,
+) -> MalRet {
+    let (mut res, mut last) = (String::new(), 0);
+    for (start, end, m) in matches {
+        res.push_str(&s[last..start]);
+        res.push_str(&f.apply(vec![m])?.pr_str(false));
+        last = end;
+    }
+    res.push_str(&s[last..]);
+    Ok(Str(res))
+}
+
+// A regex match is the string matched or, if the pattern has groups, a
+// vector of it and each group (nil for one that took no part).
+fn found(caps: &Captures) -> MalVal {
+    match caps.len() {
+        1 => Str(caps[0].to_string()),
+        _ => groups(caps),
+    }
+}
+
+fn groups(caps: &Captures) -> MalVal {
+    vector!(caps
+        .iter()
+        .map(|g| g.map_or(Nil, |g| Str(g.as_str().to_string())))
+        .collect())
+}
+
+// (re-pattern s): the regex of the source s
+fn re_pattern(a: MalArgs) -> MalRet {
+    match a[0] {
+        Str(ref s) => regex(s),
+        Regex(_) => Ok(a[0].clone()),
+        _ => typed_error("type-error", "re-pattern: expecting string arg"),
+    }
+}
+
+// the regex and the string its functions search
+fn re_args(a: &MalArgs) -> Result<(&MalRegex, &str), MalErr> {
+    match (&a[0], &a[1]) {
+        (Regex(re), Str(s)) => Ok((re, s)),
+        _ => Err(ErrTyped(
+            "type-error",
+            "expecting (regex str) args".to_string(),
+        )),
+    }
+}
+
+// (re-find re s): the first match in s, or nil
+fn re_find(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    Ok(re.captures(s).map_or(Nil, |caps| found(&caps)))
+}
+
+// (re-matches re s): the match of the whole of s, or nil
+fn re_matches(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    Ok(re.whole().captures(s).map_or(Nil, |caps| found(&caps)))
+}
+
+// (re-seq re s): the matches in s in order, or nil if there are none
+fn re_seq(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    let res: Vec<MalVal> = re.captures_iter(s).map(|caps| found(&caps)).collect();
+    match res.len() {
+        0 => Ok(Nil),
+        _ => Ok(list!(res)),
+    }
+}
+
+// (re-groups re s): the first match in s as a vector of it and its groups
+// even if it has none, or nil
+fn re_groups(a: MalArgs) -> MalRet {
+    let (re, s) = re_args(&a)?;
+    Ok(re.captures(s).map_or(Nil, |caps| groups(&caps)))
+}
+
+fn char_code(a: MalArgs) -> MalRet {
+    let mut chars = match a[0] {
+        Str(ref s) => s.chars(),
+        _ => return typed_error("type-error", "char-code: expecting string arg"),
+    };
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Int(c as i64)),
+        _ => typed_error("type-error", "char-code: expecting a one char string"),
+    }
+}
+
+// (from-char-code code ...): the string of those chars
+fn from_char_code(a: MalArgs) -> MalRet {
+    let mut s = String::new();
+    for code in a.iter() {
+        let c = match code {
+            Int(i) if *i >= 0 && *i <= u32::MAX as i64 => std::char::from_u32(*i as u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => s.push(c),
+            None => {
+                let msg = format!("from-char-code: invalid char code {}", code.pr_str(true));
+                return typed_error("type-error", &msg);
+            }
+        }
+    }
+    Ok(Str(s))
+}
+
+fn str_test(a: &MalArgs, test: fn(&str, &str) -> bool) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Str(s), Str(t)) => Ok(Bool(test(s, t))),
+        _ => typed_error("type-error", "expecting (str str) args"),
+    }
+}
+
+fn str_map(a: &MalArgs, f: fn(&str) -> String) -> MalRet {
+    match a[0] {
+        Str(ref s) => Ok(Str(f(s))),
+        _ => typed_error("type-error", "expecting (str) arg"),
+    }
+}
+
+// (ex-info msg data [cause]): an error value for throw, the map typed
+// catch* clauses see; its :type is the one in data, or :ex-info
+fn ex_info(a: MalArgs) -> MalRet {
+    let (msg, data) = match (a.first(), a.get(1)) {
+        (Some(Str(msg)), Some(data @ Hash(..))) | (Some(Str(msg)), Some(data @ Nil)) => (msg, data),
+        _ => return typed_error("type-error", "ex-info: expecting (str map [cause]) args"),
+    };
+    let kind = match data {
+        Hash(hm, _) => match hm.get(&keyword("type")) {
+            Some(k @ Keyword(_)) => k.clone(),
+            _ => keyword("ex-info"),
+        },
+        _ => keyword("ex-info"),
+    };
+    let mut kvs = vec![
+        keyword("type"),
+        kind,
+        keyword("message"),
+        Str(msg.clone()),
+        keyword("data"),
+        data.clone(),
+    ];
+    if let Some(cause) = a.get(2) {
+        kvs.extend(vec![keyword("cause"), cause.clone()]);
+    }
+    hash_map(kvs)
+}
+
+fn ex_data(a: MalArgs) -> MalRet {
+    Ok(ex_parts(&a[0])
+        .and_then(|(_, _, data)| data)
+        .cloned()
+        .unwrap_or(Nil))
+}
+
+// a caught internal error is its message
+fn ex_message(a: MalArgs) -> MalRet {
+    match ex_parts(&a[0]) {
+        Some((_, msg, _)) => Ok(Str(msg.to_string())),
+        None => match a[0] {
+            Str(ref s) => Ok(Str(s.clone())),
+            _ => Ok(Nil),
+        },
+    }
+}
+
+fn ex_cause(a: MalArgs) -> MalRet {
+    match a[0] {
+        Hash(ref hm, _) if ex_parts(&a[0]).is_some() => {
+            Ok(hm.get(&keyword("cause")).cloned().unwrap_or(Nil))
+        }
+        _ => Ok(Nil),
     }
 }
 
 pub fn ns() -> Vec<(&'static str, MalVal)> {
     vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
-        (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
-        ),
-        ("keyword", func(|a| a[0].keyword())),
-        (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
-        ),
-        ("number?", func(fn_is_type!(Int(_)))),
-        (
+        builtin!("=", [ANY] & ANY, |a| Ok(Bool(
+            a.windows(2).all(|w| w[0] == w[1])
+        ))),
+        builtin!("throw", [ANY], |a| Err(ErrMalVal(a[0].clone()))),
+        builtin!("ex-info", [STR, MAP_NIL][ANY], ex_info),
+        builtin!("ex-data", [ANY], ex_data),
+        builtin!("ex-message", [ANY], ex_message),
+        builtin!("ex-cause", [ANY], ex_cause),
+        builtin!("nil?", [ANY], fn_is_type!(Nil)),
+        builtin!("true?", [ANY], fn_is_type!(Bool(true))),
+        builtin!("false?", [ANY], fn_is_type!(Bool(false))),
+        builtin!("symbol", [STR], symbol),
+        builtin!("symbol?", [ANY], fn_is_type!(Sym(_))),
+        builtin!("string?", [ANY], fn_is_type!(Str(_))),
+        builtin!("keyword", [NAME], |a| a[0].keyword()),
+        builtin!("keyword?", [ANY], fn_is_type!(Keyword(_))),
+        builtin!("number?", [ANY], fn_is_type!(Int(_), Big(_), Float(_))),
+        builtin!("int?", [ANY], fn_is_type!(Int(_))),
+        builtin!("integer?", [ANY], fn_is_type!(Int(_), Big(_))),
+        builtin!("float?", [ANY], fn_is_type!(Float(_))),
+        builtin!("int", [NUM], int),
+        builtin!("double", [NUM], double),
+        builtin!(
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            [ANY],
+            fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))
         ),
-        (
+        builtin!(
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
-        ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
-        (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }),
-        ),
-        (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
+            [ANY],
+            fn_is_type!(MalFunc{is_macro,..} if is_macro)
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
+        builtin!("pr-str", [] & ANY, |a| Ok(Str(pr_seq(
+            &a, true, "", "", " "
+        )))),
+        builtin!("str", [] & ANY, |a| Ok(Str(pr_seq(&a, false, "", "", "")))),
+        builtin!("prn", [] & ANY, |a| {
+            println!("{}", pr_seq(&a, true, "", "", " "));
+            Ok(Nil)
+        }),
+        builtin!("println", [] & ANY, |a| {
+            println!("{}", pr_seq(&a, false, "", "", " "));
+            Ok(Nil)
+        }),
+        builtin!("read-string", [STR][STR_NIL], read_string),
+        builtin!("read-string-all", [STR][STR_NIL], read_string_all),
+        builtin!("readline", [STR], readline),
+        builtin!("slurp", [STR], fn_str!(|f| { slurp(f) })),
+        builtin!("<", [NUM] & NUM, |a| chain(&a, LT)),
+        builtin!("<=", [NUM] & NUM, |a| chain(&a, LE)),
+        builtin!(">", [NUM] & NUM, |a| chain(&a, GT)),
+        builtin!(">=", [NUM] & NUM, |a| chain(&a, GE)),
+        builtin!("+", [] & NUM, |a| fold(&a, Int(0), ADD)),
+        builtin!("-", [NUM] & NUM, |a| fold(&a, Int(0), SUB)),
+        builtin!("*", [] & NUM, |a| fold(&a, Int(1), MUL)),
+        builtin!("/", [NUM] & NUM, |a| fold(&a, Int(1), DIV)),
+        builtin!("inc", [NUM], |a| ADD(&a[0], &Int(1))),
+        builtin!("dec", [NUM], |a| SUB(&a[0], &Int(1))),
+        builtin!("quot", [NUM, NUM], |a| by_nonzero(a, QUOT)),
+        builtin!("rem", [NUM, NUM], |a| by_nonzero(a, REM)),
+        builtin!("mod", [NUM, NUM], |a| by_nonzero(a, MOD)),
+        builtin!("max", [NUM] & NUM, |a| extreme(&a, GT)),
+        builtin!("min", [NUM] & NUM, |a| extreme(&a, LT)),
+        builtin!("abs", [NUM], abs),
+        builtin!("time-ms", [], time_ms),
+        builtin!("sequential?", [ANY], fn_is_type!(List(_, _), Vector(_, _))),
+        builtin!("list", [] & ANY, |a| Ok(list!(a))),
+        builtin!("list?", [ANY], fn_is_type!(List(_, _))),
+        builtin!("vector", [] & ANY, |a| Ok(vector!(a))),
+        builtin!("vector?", [ANY], fn_is_type!(Vector(_, _))),
+        builtin!("hash-map", [] & ANY, |a| hash_map(a)),
+        builtin!("map?", [ANY], fn_is_type!(Hash(_, _))),
+        builtin!("assoc", [MAP] & ANY, assoc),
+        builtin!("dissoc", [MAP] & ANY, dissoc),
+        builtin!("get", [MAP_NIL, ANY], get),
+        builtin!("contains?", [MAP, ANY], contains_q),
+        builtin!("keys", [MAP], keys),
+        builtin!("vals", [MAP], vals),
+        builtin!("vec", [SEQ], vec),
+        builtin!("cons", [ANY, SEQ], cons),
+        builtin!("concat", [] & SEQ, concat),
+        builtin!("empty?", [SEQABLE], |a| a[0].empty_q()),
+        builtin!("nth", [SEQ, INT], nth),
+        builtin!("first", [SEQ_NIL], first),
+        builtin!("rest", [SEQ_NIL], rest),
+        builtin!("count", [SEQABLE], |a| a[0].count()),
+        builtin!("apply", [FN, ANY] & ANY, apply),
+        builtin!("map", [FN, SEQ], map),
+        builtin!("conj", [SEQ] & ANY, conj),
+        builtin!("seq", [SEQABLE], seq),
+        builtin!("subs", [STR, INT][INT], subs),
+        builtin!("string/split", [STR, STR_RE], split),
+        builtin!("string/join", [ANY][SEQ_NIL], join),
+        builtin!("upper-case", [STR], |a| str_map(&a, str::to_uppercase)),
+        builtin!("lower-case", [STR], |a| str_map(&a, str::to_lowercase)),
+        builtin!("trim", [STR], |a| str_map(&a, |s| s.trim().to_string())),
+        builtin!("starts-with?", [STR, STR], |a| str_test(&a, |s, t| s
+            .starts_with(t))),
+        builtin!("ends-with?", [STR, STR], |a| str_test(&a, |s, t| s
+            .ends_with(t))),
+        builtin!("includes?", [STR, STR], |a| str_test(&a, |s, t| s
+            .contains(t))),
+        builtin!("index-of", [STR, STR][INT], index_of),
+        // one function under two names: bare like trim and upper-case,
+        // and string/ like string/split, as clojure.string has it
+        builtin!("replace", [STR, STR_RE, STR_FN], replace),
+        builtin!("string/replace", [STR, STR_RE, STR_FN], replace),
+        builtin!("char-code", [STR], char_code),
+        builtin!("from-char-code", [] & INT, from_char_code),
+        builtin!("re-pattern", [STR_RE], re_pattern),
+        builtin!("re-find", [RE, STR], re_find),
+        builtin!("re-matches", [RE, STR], re_matches),
+        builtin!("re-seq", [RE, STR], re_seq),
+        builtin!("re-groups", [RE, STR], re_groups),
+        builtin!("meta", [ANY], |a| a[0].get_meta()),
+        builtin!("with-meta", [ANY, ANY], |a| a[0].clone().with_meta(&a[1])),
+        builtin!("atom", [ANY], |a| Ok(atom(&a[0]))),
+        builtin!("atom?", [ANY], fn_is_type!(Atom(_))),
+        builtin!("deref", [ATOM], |a| a[0].deref()),
+        builtin!("reset!", [ATOM, ANY], |a| a[0].reset_bang(&a[1])),
+        builtin!("swap!", [ATOM, FN] & ANY, |a| a[0]
+            .swap_bang(&a[1..].to_vec())),
     ]
 }
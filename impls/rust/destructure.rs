@@ -0,0 +1,342 @@
+use std::rc::Rc;
+
+use crate::pvec::PVec;
+use crate::resolve;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, Int, Keyword, List, Nil, Str, Sym, Vector};
+use crate::types::{func, hash_map, keyword, sym, typed_error, MalArgs, MalErr, MalRet, MalVal};
+
+// Destructuring in let*, loop* and fn* bindings, as in Clojure: a vector
+// pattern binds the items of a sequence ([a b & more :as all]), a map
+// pattern the values in a map ({:keys [a b] :or {b 1} :as m}), and the
+// two nest. A form that uses them is rewritten into one that only binds
+// names before it is evaluated (or resolved):
+//
+//   (let* [[a b] v] ...)   =>  (let* [vec,0 (as-seq v '[a b])
+//                                     a (seq-item vec,0 0)
+//                                     b (seq-item vec,0 1)] ...)
+//   (fn* [[a b]] ...)      =>  (fn* [arg,0] (let* [[a b] arg,0] ...))
+//   (loop* [[a b] v] ...)  =>  (let* [loop,0 v [a b] loop,0]
+//                                (loop* [loop,0 loop,0]
+//                                  (let* [[a b] loop,0] ...)))
+//
+// The names bound in between have a comma in them, which the reader takes
+// as whitespace, so they can be neither the same as nor hidden by a name
+// in the user's code. The functions called are the ones at the end of
+// this file, put in the form as values for the same reason.
+
+// the form with its patterns rewritten, if it has any
+pub fn rewrite(form: &MalVal) -> Result<Option<MalVal>, MalErr> {
+    let (l, m) = match form {
+        List(l, m) => (l, m),
+        _ => return Ok(None),
+    };
+    let items = match l.first() {
+        Some(Sym(s)) if s == "let*" => let_form(l)?,
+        Some(Sym(s)) if s == "loop*" => loop_form(l, m)?,
+        Some(Sym(s)) if s == "fn*" => fn_form(l, m),
+        _ => None,
+    };
+    Ok(items.map(|items| list_at(items, m)))
+}
+
+// generated forms are where the one they came from is, for errors
+fn list_at(items: Vec<MalVal>, m: &Rc<MalVal>) -> MalVal {
+    List(Rc::new(items.into()), m.clone())
+}
+
+// the name of a value in between
+fn hidden(kind: &str, n: usize) -> MalVal {
+    sym(&format!("{},{}", kind, n))
+}
+
+fn is_pattern(b: &MalVal) -> bool {
+    matches!(b, Vector(..) | Hash(..))
+}
+
+// the binding pairs of a let* or loop* that destructures
+fn pattern_binds(l: &PVec<MalVal>) -> Option<&PVec<MalVal>> {
+    match l.get(1) {
+        Some(List(b, _)) | Some(Vector(b, _))
+            if b.len() % 2 == 0 && b.iter().step_by(2).any(is_pattern) =>
+        {
+            Some(b)
+        }
+        _ => None,
+    }
+}
+
+// a list or vector like `like`, of other items
+fn rebuild(like: &MalVal, items: Vec<MalVal>) -> MalVal {
+    match like {
+        Vector(_, m) => Vector(Rc::new(items.into()), m.clone()),
+        List(_, m) => List(Rc::new(items.into()), m.clone()),
+        _ => list!(items),
+    }
+}
+
+fn let_form(l: &PVec<MalVal>) -> Result<Option<Vec<MalVal>>, MalErr> {
+    let binds = match pattern_binds(l) {
+        Some(binds) => binds,
+        None => return Ok(None),
+    };
+    let mut out = vec![];
+    for i in (0..binds.len()).step_by(2) {
+        bind(&binds[i], binds[i + 1].clone(), 0, &mut out)?;
+    }
+    let mut items = vec![l[0].clone(), rebuild(&l[1], out)];
+    items.extend(l.iter().skip(2).cloned());
+    Ok(Some(items))
+}
+
+// recur gives new values for the whole of each pattern, which the body
+// takes apart again
+fn loop_form(l: &PVec<MalVal>, m: &Rc<MalVal>) -> Result<Option<Vec<MalVal>>, MalErr> {
+    let binds = match pattern_binds(l) {
+        Some(binds) => binds,
+        None => return Ok(None),
+    };
+    let (mut outer, mut loop_binds, mut inner) = (vec![], vec![], vec![]);
+    for i in (0..binds.len()).step_by(2) {
+        let (b, v) = (&binds[i], &binds[i + 1]);
+        let name = match b {
+            b if is_pattern(b) => hidden("loop", i / 2),
+            b => b.clone(),
+        };
+        outer.extend(vec![name.clone(), v.clone()]);
+        loop_binds.extend(vec![name.clone(), name.clone()]);
+        if is_pattern(b) {
+            bind(b, name.clone(), 0, &mut outer)?;
+            inner.extend(vec![b.clone(), name]);
+        }
+    }
+    let body = vec![
+        sym("let*"),
+        vector!(inner),
+        l.get(2).cloned().unwrap_or(Nil),
+    ];
+    let loop_form = list_at(
+        vec![l[0].clone(), rebuild(&l[1], loop_binds), list_at(body, m)],
+        m,
+    );
+    Ok(Some(vec![sym("let*"), vector!(outer), loop_form]))
+}
+
+fn fn_form(l: &PVec<MalVal>, m: &Rc<MalVal>) -> Option<Vec<MalVal>> {
+    if resolve::multi_arity(l) {
+        let mut changed = false;
+        let mut items = vec![l[0].clone()];
+        for a in l.iter().skip(1) {
+            items.push(match a {
+                List(a, am) if a.len() == 2 => match arity(&a[0], &a[1], m) {
+                    Some((params, body)) => {
+                        changed = true;
+                        list_at(vec![params, body], am)
+                    }
+                    None => List(a.clone(), am.clone()),
+                },
+                a => a.clone(),
+            });
+        }
+        return Some(items).filter(|_| changed);
+    }
+    let (params, body) = arity(l.get(1)?, l.get(2).unwrap_or(&Nil), m)?;
+    let mut items = vec![l[0].clone(), params, body];
+    items.extend(l.iter().skip(3).cloned());
+    Some(items)
+}
+
+// params that are patterns become plain ones, which the body takes apart
+fn arity(params: &MalVal, body: &MalVal, m: &Rc<MalVal>) -> Option<(MalVal, MalVal)> {
+    let ps = match params {
+        List(ps, _) | Vector(ps, _) if ps.iter().any(is_pattern) => ps,
+        _ => return None,
+    };
+    let (mut names, mut binds) = (vec![], vec![]);
+    for (i, p) in ps.iter().enumerate() {
+        match p {
+            p if is_pattern(p) => {
+                let name = hidden("arg", i);
+                binds.extend(vec![p.clone(), name.clone()]);
+                names.push(name);
+            }
+            p => names.push(p.clone()),
+        }
+    }
+    let body = list_at(vec![sym("let*"), vector!(binds), body.clone()], m);
+    Some((rebuild(params, names), body))
+}
+
+fn call(f: fn(MalArgs) -> MalRet, args: Vec<MalVal>) -> MalVal {
+    let mut items = vec![func(f)];
+    items.extend(args);
+    list!(items)
+}
+
+fn quote(ast: &MalVal) -> MalVal {
+    list![sym("quote"), ast.clone()]
+}
+
+fn is_keyword(k: &MalVal, name: &str) -> bool {
+    matches!(k, Keyword(k) if &**k == name)
+}
+
+// appends the bindings that take `value` apart by `pattern` to out; the
+// names of the values in between are numbered by how deep they are
+fn bind(
+    pattern: &MalVal,
+    value: MalVal,
+    depth: usize,
+    out: &mut Vec<MalVal>,
+) -> Result<(), MalErr> {
+    match pattern {
+        Vector(items, _) => {
+            let seq = hidden("vec", depth);
+            out.extend(vec![seq.clone(), call(as_seq, vec![value, quote(pattern)])]);
+            let (mut it, mut i) = (items.iter(), 0);
+            while let Some(p) = it.next() {
+                match p {
+                    Sym(s) if s == "&" => match it.next() {
+                        Some(rest) => {
+                            let value = call(seq_rest, vec![seq.clone(), Int(i)]);
+                            bind(rest, value, depth + 1, out)?;
+                        }
+                        None => return pattern_error("& must be followed by a pattern"),
+                    },
+                    k if is_keyword(k, "as") => match it.next() {
+                        Some(name @ Sym(_)) => out.extend(vec![name.clone(), seq.clone()]),
+                        _ => return pattern_error(":as must be followed by a name"),
+                    },
+                    p => {
+                        let value = call(seq_item, vec![seq.clone(), Int(i)]);
+                        bind(p, value, depth + 1, out)?;
+                        i += 1;
+                    }
+                }
+            }
+        }
+        Hash(hm, _) => {
+            let map = hidden("map", depth);
+            out.extend(vec![map.clone(), call(as_map, vec![value, quote(pattern)])]);
+            let defaults = match hm.get(&keyword("or")) {
+                None => None,
+                Some(Hash(d, _)) => Some(d),
+                Some(_) => return pattern_error(":or must be a map"),
+            };
+            // a default is evaluated only if the key is missing
+            let lookup = |key: MalVal, name: &MalVal| {
+                let value = call(map_val, vec![map.clone(), key.clone()]);
+                match defaults.and_then(|d| d.get(name)) {
+                    Some(d) => list![
+                        sym("if"),
+                        call(map_has, vec![map.clone(), key]),
+                        value,
+                        d.clone()
+                    ],
+                    None => value,
+                }
+            };
+            for (k, v) in hm.iter() {
+                match k {
+                    Keyword(kind) if ["keys", "strs", "syms"].contains(&&**kind) => {
+                        let names = match v {
+                            List(names, _) | Vector(names, _) => names,
+                            _ => return pattern_error(&format!(":{} must be a vector", kind)),
+                        };
+                        for name in names.iter() {
+                            let key = match (name, &**kind) {
+                                (Sym(s), "keys") => keyword(s),
+                                (Sym(s), "strs") => Str(s.to_string()),
+                                (Sym(_), _) => quote(name),
+                                _ => return pattern_error(&format!(":{} must name names", kind)),
+                            };
+                            out.extend(vec![name.clone(), lookup(key, name)]);
+                        }
+                    }
+                    k if is_keyword(k, "as") => match v {
+                        Sym(_) => out.extend(vec![v.clone(), map.clone()]),
+                        _ => return pattern_error(":as must be followed by a name"),
+                    },
+                    k if is_keyword(k, "or") => (),
+                    k => {
+                        bind(k, lookup(quote(v), k), depth + 1, out)?;
+                    }
+                }
+            }
+        }
+        // names, and anything else for let* to refuse
+        _ => out.extend(vec![pattern.clone(), value]),
+    }
+    Ok(())
+}
+
+fn pattern_error(msg: &str) -> Result<(), MalErr> {
+    Err(ErrString(format!("destructure: {}", msg)))
+}
+
+fn shape_error(value: &MalVal, pattern: &MalVal) -> MalRet {
+    typed_error(
+        "type-error",
+        &format!(
+            "cannot destructure {} as {}",
+            value.pr_str(true),
+            pattern.pr_str(true)
+        ),
+    )
+}
+
+// (as-seq value pattern): the value, if it is a sequence (or nil)
+fn as_seq(a: MalArgs) -> MalRet {
+    match a[0] {
+        Nil | List(..) | Vector(..) => Ok(a[0].clone()),
+        _ => shape_error(&a[0], &a[1]),
+    }
+}
+
+// (as-map value pattern): the value, if it is a map (or nil), or a map
+// of the keys and values in a sequence, as the rest args of a fn* are
+fn as_map(a: MalArgs) -> MalRet {
+    match a[0] {
+        Nil | Hash(..) => Ok(a[0].clone()),
+        List(ref kvs, _) | Vector(ref kvs, _) if kvs.len() % 2 == 0 => {
+            hash_map(kvs.iter().cloned().collect())
+        }
+        _ => shape_error(&a[0], &a[1]),
+    }
+}
+
+// (seq-item seq i): the item, or nil past the end
+fn seq_item(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (List(s, _), Int(i)) | (Vector(s, _), Int(i)) => {
+            Ok(s.get(*i as usize).cloned().unwrap_or(Nil))
+        }
+        _ => Ok(Nil),
+    }
+}
+
+// (seq-rest seq i): the items from i on, or nil if there are none
+fn seq_rest(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (List(s, _), Int(i)) | (Vector(s, _), Int(i)) if s.len() > *i as usize => {
+            Ok(list!(s.iter().skip(*i as usize).cloned().collect()))
+        }
+        _ => Ok(Nil),
+    }
+}
+
+// (map-val map key): the value, or nil if there is none
+fn map_val(a: MalArgs) -> MalRet {
+    match a[0] {
+        Hash(ref hm, _) => Ok(hm.get(&a[1]).cloned().unwrap_or(Nil)),
+        _ => Ok(Nil),
+    }
+}
+
+// (map-has map key): whether there is a value for the key
+fn map_has(a: MalArgs) -> MalRet {
+    match a[0] {
+        Hash(ref hm, _) => Ok(Bool(hm.contains_key(&a[1]))),
+        _ => Ok(Bool(false)),
+    }
+}
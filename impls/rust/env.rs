@@ -3,13 +3,18 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::pvec::PVec;
+use crate::types::MalErr::{ErrString, ErrTyped};
+use crate::types::MalVal::{List, Local, Nil, Sym, Vector};
+use crate::types::{error, typed_error, MalErr, MalRet, MalVal, Symbol};
 
+// Bindings made by fn*, let* and catch* go in `slots`, in the order the
+// resolver numbers them; `data` holds globals and anything def!'d.
+// Both are also searched by name, for unresolved code.
 #[derive(Debug)]
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    data: RefCell<FnvHashMap<Symbol, MalVal>>,
+    slots: RefCell<Vec<(Symbol, MalVal)>>,
     pub outer: Option<Env>,
 }
 
@@ -21,6 +26,7 @@ pub type Env = Rc<EnvStruct>;
 pub fn env_new(outer: Option<Env>) -> Env {
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(vec![]),
         outer: outer,
     })
 }
@@ -30,14 +36,22 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Resul
     let env = env_new(outer);
     match mbinds {
         List(binds, _) | Vector(binds, _) => {
-            for (i, b) in binds.iter().enumerate() {
-                match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
-                        break;
-                    }
-                    _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
+            let (n, rest) = arity(&binds);
+            if exprs.len() < n || !rest && exprs.len() > n {
+                return Err(arity_error(&[(n, rest)], exprs.len()));
+            }
+            {
+                let mut slots = env.slots.borrow_mut();
+                for (i, b) in binds.iter().enumerate() {
+                    match b {
+                        Sym(s) if s == "&" => {
+                            if let Some(Sym(rest)) = binds.get(i + 1) {
+                                slots.push((*rest, list!(exprs[i..].to_vec())));
+                            }
+                            break;
+                        }
+                        Sym(s) => slots.push((*s, exprs[i].clone())),
+                        _ => return Err(ErrString("env_bind binds not symbols".to_string())),
                     }
                 }
             }
@@ -47,39 +61,210 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Resul
     }
 }
 
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
-    match (env.data.borrow().contains_key(key), env.outer.clone()) {
-        (true, _) => Some(env.clone()),
-        (false, Some(o)) => env_find(&o, key),
-        _ => None,
+// how many args params take before any &, and whether & takes the rest
+fn arity(binds: &PVec<MalVal>) -> (usize, bool) {
+    match binds.iter().position(|b| matches!(b, Sym(s) if s == "&")) {
+        Some(n) => (n, true),
+        None => (binds.len(), false),
+    }
+}
+
+fn arity_error(arities: &[(usize, bool)], got: usize) -> MalErr {
+    ErrTyped("arity-error", expected_args(arities, got))
+}
+
+// "expected 1 or at least 3 args, got 2", for the (count, takes rest) arities
+pub fn expected_args(arities: &[(usize, bool)], got: usize) -> String {
+    let mut counts: Vec<_> = arities.to_vec();
+    counts.sort();
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|&(n, rest)| match rest {
+            true => format!("at least {}", n),
+            false => n.to_string(),
+        })
+        .collect();
+    let expected = match counts.split_last() {
+        Some((last, init)) if !init.is_empty() => format!("{} or {}", init.join(", "), last),
+        _ => counts.join(""),
+    };
+    format!("expected {} args, got {}", expected, got)
+}
+
+// The (params body) arities of a fn* value: a multi-arity fn*, such as
+// (fn* ([x] ...) ([x y] ...)), has nil params and its arities as body.
+pub fn arities<'a>(params: &'a MalVal, ast: &'a MalVal) -> Vec<(&'a MalVal, &'a MalVal)> {
+    match (params, ast) {
+        (Nil, List(l, _)) => l
+            .iter()
+            .filter_map(|a| match a {
+                List(a, _) if a.len() == 2 => Some((&a[0], &a[1])),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![(params, ast)],
+    }
+}
+
+// the params and body a call with n args runs
+pub fn select_arity<'a>(
+    params: &'a MalVal,
+    ast: &'a MalVal,
+    n: usize,
+) -> Result<(&'a MalVal, &'a MalVal), MalErr> {
+    if let Nil = params {
+        let arities = arities(params, ast);
+        let taken: Vec<(usize, bool)> = arities
+            .iter()
+            .map(|(p, _)| match p {
+                List(b, _) | Vector(b, _) => arity(b),
+                _ => (0, false),
+            })
+            .collect();
+        let fixed = taken.iter().position(|&(k, rest)| !rest && k == n);
+        let variadic = taken.iter().position(|&(k, rest)| rest && n >= k);
+        return match fixed.or(variadic) {
+            Some(i) => Ok(arities[i]),
+            None => Err(arity_error(&taken, n)),
+        };
+    }
+    Ok((params, ast))
+}
+
+// The params of a fn*, checked when it is defined: names, with at most
+// a single & before the last, which takes the rest.
+pub fn check_params(params: &MalVal) -> Result<(), MalErr> {
+    let binds = match params {
+        List(binds, _) | Vector(binds, _) => binds,
+        _ => {
+            return Err(ErrString(
+                "fn*: params must be a list or vector".to_string(),
+            ))
+        }
+    };
+    for (i, b) in binds.iter().enumerate() {
+        match b {
+            Sym(s) if s == "&" && i + 2 != binds.len() => {
+                return Err(ErrString(
+                    "fn*: & must be followed by a single name".to_string(),
+                ))
+            }
+            Sym(_) => (),
+            _ => return Err(ErrString("fn*: params must be symbols".to_string())),
+        }
+    }
+    Ok(())
+}
+
+// checks the arities of a multi-arity fn* between them: one per number
+// of args, and at most one taking the rest
+pub fn check_arities(params: &[&MalVal]) -> Result<(), MalErr> {
+    let mut taken = vec![];
+    for p in params {
+        check_params(p)?;
+        if let List(b, _) | Vector(b, _) = p {
+            taken.push(arity(b));
+        }
+    }
+    for (i, &(n, rest)) in taken.iter().enumerate() {
+        if rest && taken.iter().skip(i + 1).any(|&(_, r)| r) {
+            return Err(ErrString(
+                "fn*: only one arity can take & rest args".to_string(),
+            ));
+        }
+        if !rest && taken.iter().skip(i + 1).any(|&a| a == (n, false)) {
+            return Err(ErrString(format!(
+                "fn*: more than one arity takes {} args",
+                n
+            )));
+        }
+    }
+    Ok(())
+}
+
+// the value bound to key in this frame (later slots shadow earlier ones)
+fn env_lookup(env: &Env, key: Symbol) -> Option<MalVal> {
+    if let Some((_, v)) = env.slots.borrow().iter().rev().find(|(s, _)| *s == key) {
+        return Some(v.clone());
+    }
+    env.data.borrow().get(&key).cloned()
+}
+
+pub fn env_find(env: &Env, key: Symbol) -> Option<Env> {
+    let mut env = env;
+    loop {
+        if env.slots.borrow().iter().any(|(s, _)| *s == key) || env.data.borrow().contains_key(&key)
+        {
+            return Some(env.clone());
+        }
+        env = env.outer.as_ref()?;
     }
 }
 
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
-        Sym(ref s) => match env_find(env, s) {
-            Some(e) => Ok(e
-                .data
-                .borrow()
-                .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
-            _ => error(&format!("'{}' not found", s)),
-        },
+        Sym(s) => {
+            let mut env = env;
+            loop {
+                if let Some(v) = env_lookup(env, *s) {
+                    return Ok(v);
+                }
+                match env.outer {
+                    Some(ref o) => env = o,
+                    None => return typed_error("not-found", &format!("'{}' not found", s)),
+                }
+            }
+        }
+        Local(depth, slot, _) => Ok(env_get_slot(env, *depth, *slot)),
         _ => error("Env.get called with non-Str"),
     }
 }
 
+pub fn env_get_slot(env: &Env, depth: usize, slot: usize) -> MalVal {
+    let mut env = env;
+    for _ in 0..depth {
+        env = env.outer.as_ref().expect("resolved frame missing");
+    }
+    let slots = env.slots.borrow();
+    slots[slot].1.clone()
+}
+
+// def! and unresolved let* overwrite a slot of the same name, if any
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
-        Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+        Sym(s) => {
+            match env
+                .slots
+                .borrow_mut()
+                .iter_mut()
+                .rev()
+                .find(|(n, _)| *n == s)
+            {
+                Some(slot) => slot.1 = val.clone(),
+                None => {
+                    env.data.borrow_mut().insert(s, val.clone());
+                }
+            }
+            Ok(val)
+        }
+        Local(_, slot, s) => {
+            env_set_slot(env, slot, s, val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
     }
 }
 
+// slots are filled in order; a repeated name reuses its slot
+pub fn env_set_slot(env: &Env, slot: usize, key: Symbol, val: MalVal) {
+    let mut slots = env.slots.borrow_mut();
+    if slot < slots.len() {
+        slots[slot] = (key, val);
+    } else {
+        slots.push((key, val));
+    }
+}
+
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    env.data.borrow_mut().insert(Symbol::new(key), val);
 }
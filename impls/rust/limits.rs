@@ -0,0 +1,184 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::types::MalErr::{ErrString, ErrTyped};
+use crate::types::MalVal::{Hash, Int, Nil};
+use crate::types::{error, keyword, MalArgs, MalErr, MalRet};
+
+// Limits on evaluation, shared by stepA's evaluator and the VM, so that a
+// runaway program fails with an error try* can catch instead of taking
+// the process down with it.
+
+thread_local! {
+    // nested evaluations: Rust calls back into an evaluator, plus the
+    // VM's own call frames
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(10_000) };
+}
+
+pub fn set_max_depth(n: usize) {
+    MAX_DEPTH.with(|m| m.set(n));
+}
+
+fn too_deep() -> MalErr {
+    ErrTyped(
+        "stack-overflow",
+        "maximum recursion depth exceeded".to_string(),
+    )
+}
+
+// held for as long as a nested evaluation runs
+pub struct Depth(());
+
+impl Depth {
+    pub fn enter() -> Result<Depth, MalErr> {
+        DEPTH.with(|d| {
+            if d.get() >= MAX_DEPTH.with(Cell::get) {
+                return Err(too_deep());
+            }
+            d.set(d.get() + 1);
+            Ok(Depth(()))
+        })
+    }
+}
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+// for the VM, which keeps its frames on the heap: whether one more on
+// top of `frames` would go past the limit
+pub fn check_depth(frames: usize) -> Result<(), MalErr> {
+    if DEPTH.with(Cell::get) + frames >= MAX_DEPTH.with(Cell::get) {
+        return Err(too_deep());
+    }
+    Ok(())
+}
+
+// A budget for evaluation: how many steps (calls, tail calls and loop*
+// iterations) it may take, and when it must be done by.
+#[derive(Clone, Copy, Default)]
+pub struct Budget {
+    pub steps: Option<u64>,
+    pub deadline: Option<Instant>,
+}
+
+// how many steps go between looks at the clock
+const CHUNK: u64 = 1024;
+
+thread_local! {
+    // the steps left are STEPS + TICKS, where TICKS counts down to the
+    // next time tick() looks at STEPS and DEADLINE
+    static STEPS: Cell<u64> = const { Cell::new(u64::MAX) };
+    static TICKS: Cell<u64> = const { Cell::new(0) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// one step of evaluation, which fails once the budget is spent
+pub fn tick() -> Result<(), MalErr> {
+    TICKS.with(|t| match t.get() {
+        0 => refill(t),
+        n => {
+            t.set(n - 1);
+            Ok(())
+        }
+    })
+}
+
+fn refill(ticks: &Cell<u64>) -> Result<(), MalErr> {
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        return Err(ErrTyped("interrupted", "interrupted".to_string()));
+    }
+    if let Some(deadline) = DEADLINE.with(Cell::get) {
+        if Instant::now() >= deadline {
+            return Err(ErrTyped(
+                "budget-exhausted",
+                "time budget exhausted".to_string(),
+            ));
+        }
+    }
+    STEPS.with(|s| match s.get() {
+        0 => Err(ErrTyped(
+            "budget-exhausted",
+            "step budget exhausted".to_string(),
+        )),
+        n => {
+            let chunk = n.min(CHUNK);
+            s.set(n - chunk);
+            ticks.set(chunk - 1);
+            Ok(())
+        }
+    })
+}
+
+// runs f within the budget, or what is left of the one already in force
+// if that is less; the steps f takes count against that one too. Once it
+// is spent every step fails, so code that catches the error cannot carry
+// on inside it.
+pub fn with_budget<T>(budget: Budget, f: impl FnOnce() -> T) -> T {
+    let left = || STEPS.with(Cell::get).saturating_add(TICKS.with(Cell::get));
+    let (outer, outer_deadline) = (left(), DEADLINE.with(Cell::get));
+    let limit = budget.steps.map_or(outer, |n| n.min(outer));
+    let deadline = match (outer_deadline, budget.deadline) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    STEPS.with(|s| s.set(limit));
+    TICKS.with(|t| t.set(0));
+    DEADLINE.with(|d| d.set(deadline));
+    let ret = f();
+    let used = limit - left();
+    STEPS.with(|s| s.set(outer - used));
+    TICKS.with(|t| t.set(0));
+    DEADLINE.with(|d| d.set(outer_deadline));
+    ret
+}
+
+// (call-with-budget {:steps n :ms n} f), behind the with-budget macro
+pub fn call_with_budget(a: MalArgs) -> MalRet {
+    let opts = match a[0] {
+        Hash(ref hm, _) => hm,
+        Nil => return a[1].apply(vec![]),
+        _ => return error("call-with-budget: expected a map of :steps and :ms"),
+    };
+    let limit = |k: &str| match opts.get(&keyword(k)) {
+        None | Some(Nil) => Ok(None),
+        Some(Int(n)) if *n >= 0 => Ok(Some(*n as u64)),
+        _ => Err(ErrString(format!(
+            "call-with-budget: :{} must be a non-negative int",
+            k
+        ))),
+    };
+    let budget = Budget {
+        steps: limit("steps")?,
+        deadline: limit("ms")?.map(|ms| Instant::now() + Duration::from_millis(ms)),
+    };
+    with_budget(budget, || a[1].apply(vec![]))
+}
+
+// Set by SIGINT, which the REPL catches while it evaluates. Like a spent
+// budget it stays set, failing every step until the next form is read,
+// so a try* cannot keep the evaluation going.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    interrupt();
+}
+
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+pub fn catch_interrupts() {
+    let handler: extern "C" fn(libc::c_int) = on_sigint;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::Relaxed);
+}
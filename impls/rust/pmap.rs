@@ -0,0 +1,310 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use fnv::FnvHasher;
+
+// Persistent hash map: a hash array mapped trie consuming five bits of
+// the key's hash per level. Updates copy only the path to the changed
+// entry; keys whose whole hash collides share a Collision node.
+
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+enum Entry<K, V> {
+    Leaf(u64, K, V),
+    Node(Rc<Node<K, V>>),
+}
+
+enum Node<K, V> {
+    Branch(u32, Vec<Entry<K, V>>),
+    // all entries are leaves with the same hash
+    Collision(Vec<Entry<K, V>>),
+}
+
+impl<K: Clone, V: Clone> Clone for Entry<K, V> {
+    fn clone(&self) -> Entry<K, V> {
+        match self {
+            Entry::Leaf(h, k, v) => Entry::Leaf(*h, k.clone(), v.clone()),
+            Entry::Node(n) => Entry::Node(n.clone()),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for Node<K, V> {
+    fn clone(&self) -> Node<K, V> {
+        match self {
+            Node::Branch(bitmap, entries) => Node::Branch(*bitmap, entries.clone()),
+            Node::Collision(entries) => Node::Collision(entries.clone()),
+        }
+    }
+}
+
+pub struct PMap<K, V> {
+    len: usize,
+    root: Rc<Node<K, V>>,
+}
+
+impl<K, V> Clone for PMap<K, V> {
+    fn clone(&self) -> PMap<K, V> {
+        PMap {
+            len: self.len,
+            root: self.root.clone(),
+        }
+    }
+}
+
+fn hash_of<K: Hash>(k: &K) -> u64 {
+    let mut h = FnvHasher::default();
+    k.hash(&mut h);
+    h.finish()
+}
+
+fn bit_pos(bitmap: u32, hash: u64, shift: u32) -> (u32, usize) {
+    let bit = 1u32 << ((hash >> shift) & MASK);
+    (bit, (bitmap & (bit - 1)).count_ones() as usize)
+}
+
+// a node holding two leaves whose hashes agree below `shift`
+fn join<K, V>(shift: u32, a: Entry<K, V>, b: Entry<K, V>) -> Node<K, V> {
+    let (ha, hb) = match (&a, &b) {
+        (Entry::Leaf(ha, ..), Entry::Leaf(hb, ..)) => (*ha, *hb),
+        _ => unreachable!(),
+    };
+    if ha == hb || shift >= 64 {
+        return Node::Collision(vec![a, b]);
+    }
+    let (ia, ib) = ((ha >> shift) & MASK, (hb >> shift) & MASK);
+    let bitmap = (1u32 << ia) | (1u32 << ib);
+    if ia == ib {
+        Node::Branch(bitmap, vec![Entry::Node(Rc::new(join(shift + BITS, a, b)))])
+    } else if ia < ib {
+        Node::Branch(bitmap, vec![a, b])
+    } else {
+        Node::Branch(bitmap, vec![b, a])
+    }
+}
+
+impl<K, V> PMap<K, V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let entries = match &*self.root {
+            Node::Branch(_, entries) | Node::Collision(entries) => entries,
+        };
+        Iter {
+            stack: vec![entries.iter()],
+            left: self.len,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl<K: Hash + Eq, V> PMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let hash = hash_of(key);
+        let mut node = &*self.root;
+        let mut shift = 0;
+        loop {
+            let entry = match node {
+                Node::Branch(bitmap, entries) => {
+                    let (bit, idx) = bit_pos(*bitmap, hash, shift);
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+                    &entries[idx]
+                }
+                Node::Collision(entries) => {
+                    return entries.iter().find_map(|e| match e {
+                        Entry::Leaf(_, k, v) if k == key => Some(v),
+                        _ => None,
+                    })
+                }
+            };
+            match entry {
+                Entry::Leaf(h, k, v) => {
+                    return if *h == hash && k == key {
+                        Some(v)
+                    } else {
+                        None
+                    };
+                }
+                Entry::Node(n) => {
+                    node = n;
+                    shift += BITS;
+                }
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> PMap<K, V> {
+    pub fn new() -> PMap<K, V> {
+        PMap {
+            len: 0,
+            root: Rc::new(Node::Branch(0, vec![])),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, val: V) {
+        let hash = hash_of(&key);
+        if PMap::insert_node(&mut self.root, 0, Entry::Leaf(hash, key, val)) {
+            self.len += 1;
+        }
+    }
+
+    // returns whether the key is new
+    fn insert_node(node: &mut Rc<Node<K, V>>, shift: u32, leaf: Entry<K, V>) -> bool {
+        let (hash, key) = match &leaf {
+            Entry::Leaf(h, k, _) => (*h, k),
+            _ => unreachable!(),
+        };
+        match Rc::make_mut(node) {
+            Node::Branch(bitmap, entries) => {
+                let (bit, idx) = bit_pos(*bitmap, hash, shift);
+                if *bitmap & bit == 0 {
+                    *bitmap |= bit;
+                    entries.insert(idx, leaf);
+                    return true;
+                }
+                match &mut entries[idx] {
+                    Entry::Node(child) => PMap::insert_node(child, shift + BITS, leaf),
+                    Entry::Leaf(h, k, _) if *h == hash && k == key => {
+                        entries[idx] = leaf;
+                        false
+                    }
+                    Entry::Leaf(..) => {
+                        let empty = Entry::Node(Rc::new(Node::Collision(vec![])));
+                        let old = std::mem::replace(&mut entries[idx], empty);
+                        entries[idx] = Entry::Node(Rc::new(join(shift + BITS, old, leaf)));
+                        true
+                    }
+                }
+            }
+            Node::Collision(entries) => {
+                match entries
+                    .iter()
+                    .position(|e| matches!(e, Entry::Leaf(_, k, _) if k == key))
+                {
+                    Some(i) => {
+                        entries[i] = leaf;
+                        false
+                    }
+                    None => {
+                        entries.push(leaf);
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if self.contains_key(key) {
+            PMap::remove_node(&mut self.root, 0, hash_of(key), key);
+            self.len -= 1;
+        }
+    }
+
+    // the key must be present
+    fn remove_node(node: &mut Rc<Node<K, V>>, shift: u32, hash: u64, key: &K) {
+        match Rc::make_mut(node) {
+            Node::Branch(bitmap, entries) => {
+                let (bit, idx) = bit_pos(*bitmap, hash, shift);
+                let lone = match &mut entries[idx] {
+                    Entry::Leaf(..) => {
+                        entries.remove(idx);
+                        *bitmap &= !bit;
+                        return;
+                    }
+                    Entry::Node(child) => {
+                        PMap::remove_node(child, shift + BITS, hash, key);
+                        PMap::lone_leaf(child)
+                    }
+                };
+                if let Some(leaf) = lone {
+                    entries[idx] = leaf;
+                }
+            }
+            Node::Collision(entries) => {
+                entries.retain(|e| !matches!(e, Entry::Leaf(_, k, _) if k == key));
+            }
+        }
+    }
+
+    // a child left holding a single leaf is replaced by that leaf
+    // (children start with two entries, so they never become empty)
+    fn lone_leaf(node: &Rc<Node<K, V>>) -> Option<Entry<K, V>> {
+        let entries = match &**node {
+            Node::Branch(_, entries) | Node::Collision(entries) => entries,
+        };
+        match entries.as_slice() {
+            [leaf @ Entry::Leaf(..)] => Some(leaf.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Default for PMap<K, V> {
+    fn default() -> PMap<K, V> {
+        PMap::new()
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq> PartialEq for PMap<K, V> {
+    fn eq(&self, other: &PMap<K, V>) -> bool {
+        self.len == other.len && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for PMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// depth first over the trie
+pub struct Iter<'a, K, V> {
+    stack: Vec<std::slice::Iter<'a, Entry<K, V>>>,
+    left: usize,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            match self.stack.last_mut()?.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(Entry::Leaf(_, k, v)) => {
+                    self.left -= 1;
+                    return Some((k, v));
+                }
+                Some(Entry::Node(n)) => match &**n {
+                    Node::Branch(_, entries) | Node::Collision(entries) => {
+                        self.stack.push(entries.iter())
+                    }
+                },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
@@ -1,16 +1,56 @@
 use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Big, Bool, Float, Func, Hash, Int, Keyword, List, Local, MalFunc, Nil, Regex, Str, Sym,
+    Vector,
+};
 
+// inverse of the reader's unescape_str: the result reads back as the
+// same string
 fn escape_str(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            '"' => "\\\"".to_string(),
-            '\n' => "\\n".to_string(),
-            '\\' => "\\\\".to_string(),
-            _ => c.to_string(),
-        })
-        .collect::<Vec<String>>()
-        .join("")
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            '\0' => res.push_str("\\0"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+// inverse of the reader's unescape_regex: the pattern's own escapes are
+// kept, only a bare '"' needs one
+fn escape_regex(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                res.push(c);
+                res.extend(chars.next());
+            }
+            '"' => res.push_str("\\\""),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+// always prints a '.' or an exponent so the result reads back as a
+// float; infinities and NaN use Clojure's symbolic values
+fn pr_float(f: f64) -> String {
+    if f.is_nan() {
+        String::from("##NaN")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "##Inf" } else { "##-Inf" })
+    } else {
+        format!("{:?}", f)
+    }
 }
 
 impl MalVal {
@@ -20,42 +60,47 @@ impl MalVal {
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            Big(b) => b.to_string(),
+            Float(f) => pr_float(*f),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
-            Sym(s) => s.clone(),
+            Sym(s) | Local(_, _, s) => s.to_string(),
+            Keyword(k) => format!(":{}", k),
+            Regex(re) if print_readably => format!("#\"{}\"", escape_regex(re.as_str())),
+            Regex(re) => re.as_str().to_string(),
             List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
             Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                     .collect();
                 pr_seq(&l, print_readably, "{", "}", " ")
             }
             Func(f, _) => format!("#<fn {:?}>", f),
             MalFunc {
                 ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
+            } => match (&**p, &**a) {
+                (Nil, List(arities, _)) => pr_seq(arities.iter(), true, "(fn* ", ")", " "),
+                _ => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
+            },
             Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
         }
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+pub fn pr_seq<'a, I: IntoIterator<Item = &'a MalVal>>(
+    seq: I,
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
 ) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
+    let strs: Vec<String> = seq.into_iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
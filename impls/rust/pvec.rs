@@ -0,0 +1,371 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Index;
+use std::rc::Rc;
+
+// Persistent vector: a 32-way trie of leaves plus a separate tail
+// leaf, so pushes only copy the path to the tail (Clojure's
+// PersistentVector). `start` hides a prefix, which makes `slice`
+// (and so `rest`) O(1) without copying anything. Elements put in
+// front (`cons`, `conj` on a list) go to a second vector, kept
+// reversed, so prepending is a push and dropping them again a pop.
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+enum Node<T> {
+    Branch(Vec<Rc<Node<T>>>),
+    Leaf(Vec<T>),
+}
+
+impl<T: Clone> Clone for Node<T> {
+    fn clone(&self) -> Node<T> {
+        match self {
+            Node::Branch(children) => Node::Branch(children.clone()),
+            Node::Leaf(items) => Node::Leaf(items.clone()),
+        }
+    }
+}
+
+pub struct PVec<T> {
+    cnt: usize, // elements in root and tail, including hidden ones
+    start: usize,
+    shift: u32,
+    root: Rc<Node<T>>,
+    tail: Rc<Vec<T>>,
+    front: Option<Rc<PVec<T>>>,
+}
+
+impl<T> Clone for PVec<T> {
+    fn clone(&self) -> PVec<T> {
+        PVec {
+            cnt: self.cnt,
+            start: self.start,
+            shift: self.shift,
+            root: self.root.clone(),
+            tail: self.tail.clone(),
+            front: self.front.clone(),
+        }
+    }
+}
+
+fn new_path<T>(level: u32, node: Rc<Node<T>>) -> Rc<Node<T>> {
+    if level == 0 {
+        node
+    } else {
+        Rc::new(Node::Branch(vec![new_path(level - BITS, node)]))
+    }
+}
+
+impl<T> PVec<T> {
+    pub fn new() -> PVec<T> {
+        PVec {
+            cnt: 0,
+            start: 0,
+            shift: BITS,
+            root: Rc::new(Node::Branch(vec![])),
+            tail: Rc::new(vec![]),
+            front: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.front_len() + self.cnt - self.start
+    }
+
+    fn front_len(&self) -> usize {
+        self.front.as_ref().map_or(0, |f| f.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn tail_offset(&self) -> usize {
+        if self.cnt < WIDTH {
+            0
+        } else {
+            ((self.cnt - 1) >> BITS) << BITS
+        }
+    }
+
+    // the leaf (or tail) holding absolute index i
+    fn leaf_for(&self, i: usize) -> &[T] {
+        if i >= self.tail_offset() {
+            return &self.tail;
+        }
+        let mut node = &*self.root;
+        let mut level = self.shift;
+        loop {
+            match node {
+                Node::Branch(children) => {
+                    node = &children[(i >> level) & MASK];
+                    level -= BITS;
+                }
+                Node::Leaf(items) => return items,
+            }
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        let pre = self.front_len();
+        if i < pre {
+            let front = self.front.as_ref().unwrap();
+            front.get(pre - 1 - i)
+        } else if i < self.len() {
+            let i = i - pre + self.start;
+            Some(&self.leaf_for(i)[i & MASK])
+        } else {
+            None
+        }
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        match self.len() {
+            0 => None,
+            n => self.get(n - 1),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            pre: self.front.as_ref().map(|f| Box::new(f.iter().rev())),
+            vec: self,
+            chunk: [].iter(),
+            front: self.start,
+            back: self.cnt,
+        }
+    }
+}
+
+impl<T: Clone> PVec<T> {
+    pub fn push(&mut self, val: T) {
+        if self.cnt - self.tail_offset() < WIDTH {
+            Rc::make_mut(&mut self.tail).push(val);
+            self.cnt += 1;
+            return;
+        }
+        // the tail is full: move it into the trie
+        let full = std::mem::replace(&mut self.tail, Rc::new(vec![val]));
+        let leaf = Rc::new(Node::Leaf(
+            Rc::try_unwrap(full).unwrap_or_else(|t| (*t).clone()),
+        ));
+        if (self.cnt >> BITS) > (1 << self.shift) {
+            let old = std::mem::replace(&mut self.root, Rc::new(Node::Branch(vec![])));
+            self.root = Rc::new(Node::Branch(vec![old, new_path(self.shift, leaf)]));
+            self.shift += BITS;
+        } else {
+            PVec::push_tail(self.cnt, self.shift, &mut self.root, leaf);
+        }
+        self.cnt += 1;
+    }
+
+    // puts val before the first element
+    pub fn push_front(&mut self, val: T) {
+        match self.front {
+            Some(ref mut front) => Rc::make_mut(front).push(val),
+            None => self.front = Some(Rc::new(PVec::from(vec![val]))),
+        }
+    }
+
+    // the elements from `from` on, sharing structure with self
+    pub fn slice(&self, from: usize) -> PVec<T> {
+        let mut v = self.clone();
+        let pre = self.front_len();
+        if from < pre {
+            let front = Rc::make_mut(v.front.as_mut().unwrap());
+            for _ in 0..from {
+                front.pop();
+            }
+        } else {
+            v.front = None;
+            v.start = (self.start + from - pre).min(self.cnt);
+        }
+        v
+    }
+
+    // drops the last element; only used on front vectors, which are
+    // never sliced, so start is 0
+    fn pop(&mut self) {
+        if self.cnt == 1 || self.cnt - self.tail_offset() > 1 {
+            Rc::make_mut(&mut self.tail).pop();
+            self.cnt -= 1;
+            return;
+        }
+        // the tail is used up: the last leaf of the trie becomes the tail
+        self.tail = Rc::new(self.leaf_for(self.cnt - 2).to_vec());
+        PVec::pop_tail(self.cnt, self.shift, &mut self.root);
+        self.cnt -= 1;
+        let only_child = match *self.root {
+            Node::Branch(ref children) if self.shift > BITS && children.len() == 1 => {
+                Some(children[0].clone())
+            }
+            _ => None,
+        };
+        if let Some(child) = only_child {
+            self.root = child;
+            self.shift -= BITS;
+        }
+    }
+
+    // removes the last leaf, returning whether parent is left empty
+    fn pop_tail(cnt: usize, level: u32, parent: &mut Rc<Node<T>>) -> bool {
+        let idx = ((cnt - 2) >> level) & MASK;
+        match Rc::make_mut(parent) {
+            Node::Branch(children) => {
+                if level == BITS || PVec::pop_tail(cnt, level - BITS, &mut children[idx]) {
+                    children.truncate(idx);
+                }
+                children.is_empty()
+            }
+            Node::Leaf(_) => false,
+        }
+    }
+
+    fn push_tail(cnt: usize, level: u32, parent: &mut Rc<Node<T>>, leaf: Rc<Node<T>>) {
+        let idx = ((cnt - 1) >> level) & MASK;
+        if let Node::Branch(children) = Rc::make_mut(parent) {
+            if level == BITS {
+                children.push(leaf);
+            } else if idx < children.len() {
+                PVec::push_tail(cnt, level - BITS, &mut children[idx], leaf);
+            } else {
+                children.push(new_path(level - BITS, leaf));
+            }
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T> Default for PVec<T> {
+    fn default() -> PVec<T> {
+        PVec::new()
+    }
+}
+
+impl<T> Index<usize> for PVec<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        match self.get(i) {
+            Some(v) => v,
+            None => panic!("index {} out of range for length {}", i, self.len()),
+        }
+    }
+}
+
+impl<T: Clone> std::iter::FromIterator<T> for PVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> PVec<T> {
+        let mut v = PVec::new();
+        v.extend(iter);
+        v
+    }
+}
+
+impl<T: Clone> Extend<T> for PVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for x in iter {
+            self.push(x);
+        }
+    }
+}
+
+impl<T: Clone> From<Vec<T>> for PVec<T> {
+    fn from(v: Vec<T>) -> PVec<T> {
+        v.into_iter().collect()
+    }
+}
+
+impl<T: PartialEq> PartialEq for PVec<T> {
+    fn eq(&self, other: &PVec<T>) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Hash> Hash for PVec<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for x in self.iter() {
+            x.hash(state);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// walks the front elements, then a leaf at a time through the rest
+pub struct Iter<'a, T> {
+    pre: Option<Box<std::iter::Rev<Iter<'a, T>>>>,
+    vec: &'a PVec<T>,
+    chunk: std::slice::Iter<'a, T>,
+    front: usize,
+    back: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if let Some(ref mut pre) = self.pre {
+            match pre.next() {
+                Some(x) => return Some(x),
+                None => self.pre = None,
+            }
+        }
+        if self.front >= self.back {
+            return None;
+        }
+        if self.chunk.len() == 0 {
+            let leaf = self.vec.leaf_for(self.front);
+            let off = self.front & MASK;
+            let end = leaf.len().min(off + self.back - self.front);
+            self.chunk = leaf[off..end].iter();
+        }
+        self.front += 1;
+        self.chunk.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.back - self.front + self.pre.as_ref().map_or(0, |pre| pre.len());
+        (n, Some(n))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        if self.front >= self.back {
+            return self.pre.as_mut().and_then(|pre| pre.next_back());
+        }
+        self.back -= 1;
+        // the buffered chunk always ends at or before back
+        let unbuffered = self.back + 1 - self.front - self.chunk.len();
+        if unbuffered == 0 {
+            return self.chunk.next_back();
+        }
+        let i = self.back;
+        Some(&self.vec.leaf_for(i)[i & MASK])
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {}
+
+impl<'a, T> IntoIterator for &'a PVec<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
@@ -1,83 +1,347 @@
-use regex::{Captures, Regex};
 use std::rc::Rc;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::bigint::BigInt;
+use crate::types::MalErr::{ErrAt, ErrString, ErrTyped};
+use crate::types::MalVal::{Bool, Float, Hash, Int, List, Nil, Str, Vector};
+use crate::types::{
+    error, format_error, hash_map, integer, keyword, regex, sym, Loc, MalErr, MalRet, MalVal,
+};
 
+// a token borrowed from the source text, with the position of its
+// first character
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    col: usize,
+}
+
+// byte-oriented lexer; yields tokens on demand, skipping whitespace,
+// commas and comments
 #[derive(Debug, Clone)]
-struct Reader {
-    tokens: Vec<String>,
+struct Lexer<'a> {
+    src: &'a str,
     pos: usize,
+    line: usize,
+    col: usize,
+}
+
+fn is_delim(b: u8) -> bool {
+    match b {
+        b'[' | b']' | b'{' | b'}' | b'(' | b')' | b'\'' | b'"' | b'`' | b',' | b';' => true,
+        _ => b.is_ascii_whitespace() || b == 0x0b,
+    }
 }
 
-impl Reader {
-    fn next(&mut self) -> Result<String, MalErr> {
-        self.pos = self.pos + 1;
-        Ok(self
-            .tokens
-            .get(self.pos - 1)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Lexer<'a> {
+        Lexer {
+            src,
+            pos: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn peek_byte(&self) -> Option<u8> {
+        self.src.as_bytes().get(self.pos).cloned()
+    }
+
+    fn bump(&mut self) {
+        let b = self.src.as_bytes()[self.pos];
+        self.pos += 1;
+        if b == b'\n' {
+            self.line += 1;
+            self.col = 1;
+        } else if b & 0xc0 != 0x80 {
+            // count characters, not UTF-8 continuation bytes
+            self.col += 1;
+        }
+    }
+
+    fn skip_blank(&mut self) {
+        while let Some(b) = self.peek_byte() {
+            match b {
+                b';' => {
+                    while let Some(b) = self.peek_byte() {
+                        if b == b'\n' {
+                            break;
+                        }
+                        self.bump();
+                    }
+                }
+                b',' | 0x0b => self.bump(),
+                _ if b.is_ascii_whitespace() => self.bump(),
+                _ => break,
+            }
+        }
+    }
+
+    // the closing quote is optional so that an unterminated string is
+    // still a single token
+    fn string(&mut self) {
+        self.bump();
+        while let Some(b) = self.peek_byte() {
+            self.bump();
+            match b {
+                b'"' => break,
+                b'\\' if self.pos < self.src.len() => self.bump(),
+                _ => (),
+            }
+        }
+    }
+
+    // position of the next token, or of the end of input
+    fn loc(&mut self) -> (usize, usize) {
+        self.skip_blank();
+        (self.line, self.col)
     }
-    fn peek(&self) -> Result<String, MalErr> {
-        Ok(self
-            .tokens
-            .get(self.pos)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        self.skip_blank();
+        let (start, line, col) = (self.pos, self.line, self.col);
+        match self.peek_byte()? {
+            b'~' if self.src.as_bytes().get(start + 1) == Some(&b'@') => {
+                self.bump();
+                self.bump();
+            }
+            b'[' | b']' | b'{' | b'}' | b'(' | b')' | b'\'' | b'`' | b'~' | b'^' | b'@' => {
+                self.bump()
+            }
+            b'#' if self.src.as_bytes().get(start + 1) == Some(&b'"') => {
+                self.bump();
+                self.string();
+            }
+            b'"' => self.string(),
+            _ => {
+                while let Some(b) = self.peek_byte() {
+                    if is_delim(b) {
+                        break;
+                    }
+                    self.bump();
+                }
+            }
+        }
+        Some(Token {
+            text: &self.src[start..self.pos],
+            line,
+            col,
+        })
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
-        )
-        .unwrap();
+// true if the token is a string with a closing quote
+fn is_closed_str(token: &str) -> bool {
+    let b = token.as_bytes();
+    if b.len() < 2 || b[b.len() - 1] != b'"' {
+        return false;
     }
+    // the final quote must not be escaped
+    let backslashes = b[1..b.len() - 1]
+        .iter()
+        .rev()
+        .take_while(|&&c| c == b'\\')
+        .count();
+    backslashes % 2 == 0
+}
 
-    let mut res = vec![];
-    for cap in RE.captures_iter(str) {
-        if cap[1].starts_with(";") {
-            continue;
+#[derive(Debug, Clone)]
+struct Reader<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<Token<'a>>,
+    file: Option<Rc<String>>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(str: &'a str, file: Option<&str>) -> Reader<'a> {
+        Reader {
+            lexer: Lexer::new(str),
+            peeked: None,
+            file: file.map(|f| Rc::new(f.to_string())),
+        }
+    }
+    fn next(&mut self) -> Result<Token<'a>, MalErr> {
+        match self.peeked.take() {
+            Some(t) => Ok(t),
+            None => self
+                .lexer
+                .next()
+                .ok_or_else(|| ErrString("underflow".to_string())),
+        }
+    }
+    fn peek(&mut self) -> Result<Token<'a>, MalErr> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next();
+        }
+        self.peeked
+            .ok_or_else(|| ErrString("underflow".to_string()))
+    }
+    // location of the next token (or of the end of input)
+    fn loc(&mut self) -> Loc {
+        let (line, col) = match self.peeked {
+            Some(t) => (t.line, t.col),
+            None => self.lexer.loc(),
+        };
+        Loc {
+            file: self.file.clone(),
+            line,
+            col,
+        }
+    }
+}
+
+fn error_at(loc: Loc, msg: &str) -> MalRet {
+    Err(ErrAt(
+        Box::new(ErrTyped("reader-error", msg.to_string())),
+        loc,
+    ))
+}
+
+// digits of a \uXXXX or \u{X...} escape; `chars` is just past the 'u'
+fn unescape_unicode(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, String> {
+    let mut hex = String::new();
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) if hex.len() < 6 => hex.push(c),
+                _ => return Err("invalid escape '\\u{...}' in string".to_string()),
+            }
+        }
+    } else {
+        hex.extend(chars.take(4));
+        if hex.chars().count() < 4 {
+            hex.clear();
+        }
+    }
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("invalid escape '\\u' in string, expected 4 hex digits or {...}".to_string());
+    }
+    // at most 6 hex digits, so this always fits
+    let n = u32::from_str_radix(&hex, 16).unwrap();
+    std::char::from_u32(n)
+        .ok_or_else(|| format!("invalid unicode scalar value '\\u{{{}}}' in string", hex))
+}
+
+fn unescape_str(s: &str) -> Result<String, String> {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => res.push('\n'),
+                Some('t') => res.push('\t'),
+                Some('r') => res.push('\r'),
+                Some('0') => res.push('\0'),
+                Some('"') => res.push('"'),
+                Some('\\') => res.push('\\'),
+                Some('u') => res.push(unescape_unicode(&mut chars)?),
+                Some(c) => return Err(format!("invalid escape '\\{}' in string", c)),
+                None => return Err("invalid escape at end of string".to_string()),
+            },
+            _ => res.push(c),
+        }
+    }
+    Ok(res)
+}
+
+// a #"..." regex is written as the pattern itself, but for the '"'s in
+// it, which are escaped
+fn unescape_regex(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('"') => res.push('"'),
+                Some(c) => {
+                    res.push('\\');
+                    res.push(c);
+                }
+                None => res.push('\\'),
+            },
+            _ => res.push(c),
         }
-        res.push(String::from(&cap[1]));
     }
     res
 }
 
-fn unescape_str(s: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+fn is_int(token: &str) -> bool {
+    let digits = token.strip_prefix('-').unwrap_or(token);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+// digits with a fraction and/or an exponent: 1.5, 2., -3e10, 4.5E-3
+fn is_float(token: &str) -> bool {
+    let b = token.strip_prefix('-').unwrap_or(token).as_bytes();
+    let digits = |i: usize| b[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+    let mut i = digits(0);
+    if i == 0 {
+        return false;
+    }
+    let mut float = false;
+    if i < b.len() && b[i] == b'.' {
+        i += 1 + digits(i + 1);
+        float = true;
+    }
+    if i < b.len() && (b[i] == b'e' || b[i] == b'E') {
+        i += 1;
+        if i < b.len() && (b[i] == b'+' || b[i] == b'-') {
+            i += 1;
+        }
+        let exp = digits(i);
+        if exp == 0 {
+            return false;
+        }
+        i += exp;
+        float = true;
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
-    })
-    .to_string()
+    float && i == b.len()
 }
 
 fn read_atom(rdr: &mut Reader) -> MalRet {
-    lazy_static! {
-        static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
-        static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
-    }
-    let token = rdr.next()?;
-    match &token[..] {
+    let loc = rdr.loc();
+    let token = rdr.next()?.text;
+    match token {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
+        "##Inf" => Ok(Float(f64::INFINITY)),
+        "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+        "##NaN" => Ok(Float(f64::NAN)),
         _ => {
-            if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
-            } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
-            } else if token.starts_with("\"") {
-                error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
+            if is_int(token) {
+                match (token.parse(), BigInt::parse(token)) {
+                    (Ok(i), _) => Ok(Int(i)),
+                    (Err(_), Some(b)) => Ok(integer(b)),
+                    _ => error_at(loc, "invalid integer literal"),
+                }
+            } else if is_float(token) {
+                match token.parse() {
+                    Ok(f) => Ok(Float(f)),
+                    Err(_) => error_at(loc, "invalid float literal"),
+                }
+            } else if token.starts_with("#\"") && is_closed_str(&token[1..]) {
+                regex(&unescape_regex(&token[2..token.len() - 1]))
+                    .or_else(|e| error_at(loc, &format_error(e)))
+            } else if token.starts_with("#\"") {
+                error_at(loc, "expected '\"', got EOF")
+            } else if is_closed_str(token) {
+                match unescape_str(&token[1..token.len() - 1]) {
+                    Ok(s) => Ok(Str(s)),
+                    Err(msg) => error_at(loc, &msg),
+                }
+            } else if token.starts_with('"') {
+                error_at(loc, "expected '\"', got EOF")
+            } else if token.starts_with(':') {
+                Ok(keyword(&token[1..]))
             } else {
-                Ok(Sym(token.to_string()))
+                Ok(sym(token))
             }
         }
     }
@@ -85,11 +349,12 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
 
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
+    let loc = rdr.loc();
     rdr.next()?;
     loop {
         let token = match rdr.peek() {
-            Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Ok(t) => t.text,
+            Err(_) => return error_at(loc, &format!("expected '{}', got EOF", end)),
         };
         if token == end {
             break;
@@ -97,60 +362,137 @@ fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
         seq.push(read_form(rdr)?)
     }
     let _ = rdr.next();
+    let meta = Rc::new(loc.to_meta());
     match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
+        ")" => Ok(List(Rc::new(seq.into()), meta)),
+        "]" => Ok(Vector(Rc::new(seq.into()), meta)),
+        "}" => match hash_map(seq) {
+            Ok(Hash(hm, _)) => Ok(Hash(hm, meta)),
+            Ok(_) => error("read_seq invalid hash-map"),
+            Err(e) => error_at(loc, &format_error(e)),
+        },
         _ => error("read_seq unknown end value"),
     }
 }
 
 fn read_form(rdr: &mut Reader) -> MalRet {
-    let token = rdr.peek()?;
-    match &token[..] {
+    let token = rdr.peek()?.text;
+    match token {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![sym("quote"), read_form(rdr)?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![sym("quasiquote"), read_form(rdr)?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![sym("unquote"), read_form(rdr)?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![sym("splice-unquote"), read_form(rdr)?])
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            Ok(list![sym("with-meta"), read_form(rdr)?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            Ok(list![sym("deref"), read_form(rdr)?])
         }
-        ")" => error("unexpected ')'"),
+        ")" => error_at(rdr.loc(), "unexpected ')'"),
         "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
+        "]" => error_at(rdr.loc(), "unexpected ']'"),
         "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
+        "}" => error_at(rdr.loc(), "unexpected '}'"),
         "{" => read_seq(rdr, "}"),
         _ => read_atom(rdr),
     }
 }
 
 pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
+    read_source(&str, None)
+}
+
+// read a form, recording `file` in the location of every list,
+// vector and hash-map
+pub fn read_source(str: &str, file: Option<&str>) -> MalRet {
+    let mut rdr = Reader::new(str, file);
+    if rdr.peek().is_err() {
         return error("no input");
     }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens: tokens,
-    })
+    read_form(&mut rdr)
+}
+
+// read every top-level form; unlike read_source, trailing input that
+// is not a complete form (e.g. a stray ')') is an error
+pub fn read_all(str: &str, file: Option<&str>) -> Result<Vec<MalVal>, MalErr> {
+    let mut rdr = Reader::new(str, file);
+    let mut forms = vec![];
+    while rdr.peek().is_ok() {
+        forms.push(read_form(&mut rdr)?);
+    }
+    Ok(forms)
+}
+
+// true if the input ends inside an unclosed list, vector, hash-map or
+// string, or right after a reader macro, i.e. more lines could
+// complete it
+pub fn incomplete(str: &str) -> bool {
+    let mut depth = 0;
+    let mut last = "";
+    for t in Lexer::new(str) {
+        match t.text {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" if depth == 0 => return false,
+            ")" | "]" | "}" => depth -= 1,
+            s if s.starts_with('"') && !is_closed_str(s) => return true,
+            s if s.starts_with("#\"") && !is_closed_str(&s[1..]) => return true,
+            _ => (),
+        }
+        last = t.text;
+    }
+    depth > 0 || ["'", "`", "~", "~@", "^", "@"].contains(&last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::incomplete;
+
+    #[test]
+    fn unclosed_input_is_incomplete() {
+        for src in [
+            "(+ 1", "[1 2", "{:a 1", "(a [b {", "\"abc", "\"ab\\\"", "#\"a", "'", "(a `", "~@",
+            "(a ^", "^", "@",
+        ] {
+            assert!(incomplete(src), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn complete_or_broken_input_is_not() {
+        for src in [
+            "",
+            "(+ 1 2)",
+            "[1 {:a \"(\"}]",
+            "\"a\\\\\"",
+            "#\"(\"",
+            "'a",
+            "(a))",
+            ")",
+            "; (",
+            "1 ; (",
+        ] {
+            assert!(!incomplete(src), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn parens_in_comments_do_not_count() {
+        assert!(incomplete("(a ; )"));
+        assert!(!incomplete("(a ; (\n)"));
+    }
 }
@@ -0,0 +1,583 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use fnv::FnvHashSet;
+
+use crate::bodies::BodyCache;
+use crate::destructure;
+use crate::env::{check_arities, env_get, Env};
+use crate::pvec::PVec;
+use crate::types::MalVal::{Hash, List, Local, MalFunc, Nil, Sym, Vector};
+use crate::types::{error, MalErr, MalMap, MalVal, Symbol};
+
+// Compiles a fn* body, when the closure is created, so that names bound
+// inside it (parameters, let*, loop* and catch* bindings) are read
+// straight from a frame slot instead of being looked up by name:
+//
+//   1. macro calls are expanded, so that what is left is plain code;
+//   2. each scope collects the names def!'d in it, since those can only be
+//      found at run time and so stay symbols;
+//   3. every other reference to a bound name becomes Local(depth, slot).
+//
+// Free names are left as symbols and looked up in the environment chain.
+// The slots must be numbered exactly as env_bind and let* fill them.
+
+const SPECIAL_FORMS: &[&str] = &[
+    "def!",
+    "defmacro!",
+    "let*",
+    "loop*",
+    "recur",
+    "fn*",
+    "do",
+    "if",
+    "try*",
+    "eval",
+    "quote",
+    "quasiquote",
+    "quasiquoteexpand",
+    "macroexpand",
+];
+
+struct Scope {
+    slots: Vec<Symbol>,
+    defs: FnvHashSet<Symbol>,
+}
+
+// The environment macros are looked up in while expanding, and each name
+// looked up there at the head of a call with the macro it named (None
+// for a name that was not a macro then).
+struct Macros<'a> {
+    env: &'a Env,
+    seen: Vec<(Symbol, Option<MalVal>)>,
+}
+
+// A body as resolved when a fn* form was last evaluated, and the macros
+// it was expanded with.
+struct Resolved {
+    seen: Vec<(Symbol, Option<MalVal>)>,
+    body: Rc<MalVal>,
+}
+
+thread_local! {
+    static RESOLVED: RefCell<BodyCache<Rc<Resolved>>> = RefCell::new(BodyCache::new());
+}
+
+// The body of a fn* form that is not itself inside a resolved body (one
+// at top level) is resolved each time the form is evaluated, in the
+// environment of that moment. What that depends on is which names were
+// macros, so the last result is kept, by the form's own body, and used
+// again while each name it looked up still names the same macro, or still
+// none; the closures then share one body, and so what was derived from it.
+pub fn resolve_fn(params: &MalVal, body: &MalVal, env: &Env) -> Rc<MalVal> {
+    if let Some(r) = RESOLVED.with(|c| c.borrow().get(body)) {
+        if r.seen
+            .iter()
+            .all(|(s, m)| same_macro(&macro_named(env, *s), m))
+        {
+            return r.body.clone();
+        }
+    }
+    let mut macros = Macros { env, seen: vec![] };
+    let resolved = Rc::new(resolve_body(params, body, &mut macros));
+    let r = Resolved {
+        seen: macros.seen,
+        body: resolved.clone(),
+    };
+    RESOLVED.with(|c| c.borrow_mut().insert(body, Rc::new(r)));
+    resolved
+}
+
+fn resolve_body(params: &MalVal, body: &MalVal, macros: &mut Macros) -> MalVal {
+    if let (Nil, List(arities, m)) = (params, body) {
+        return map_tail(arities, m, 0, &mut |a| match a {
+            List(a, am) => map_tail(a, am, 1, &mut |body| resolve_body(&a[0], body, macros)),
+            _ => a.clone(),
+        });
+    }
+    let slots = match param_slots(params) {
+        Some(slots) => slots,
+        None => return body.clone(),
+    };
+    let body = expand(body, macros, &mut slots.clone());
+    let mut scopes = vec![];
+    resolve_scope(slots, &[body], &mut scopes).pop().unwrap()
+}
+
+// the macro calls in a form that is not resolved (a top-level form)
+// expanded, for a loop* whose recurs must be seen before it runs
+pub fn expand_macros(ast: &MalVal, env: &Env) -> MalVal {
+    expand(ast, &mut Macros { env, seen: vec![] }, &mut vec![])
+}
+
+// The arities of a multi-arity fn*, (fn* ([x] body) ([x y] body) ...),
+// checked and made the body of its closures (whose params are nil), or
+// None for a fn* of one arity.
+pub fn fn_arities(l: &PVec<MalVal>) -> Option<Result<MalVal, MalErr>> {
+    if !multi_arity(l) {
+        return None;
+    }
+    let arities: Vec<MalVal> = l.iter().skip(1).cloned().collect();
+    let mut params = vec![];
+    for a in arities.iter() {
+        match a {
+            List(a, _) if a.len() == 2 => params.push(&a[0]),
+            _ => return Some(error("fn*: each arity must be (params body)")),
+        }
+    }
+    Some(check_arities(&params).map(|_| list!(arities)))
+}
+
+// every form after fn* is a list that starts with params
+pub fn multi_arity(l: &PVec<MalVal>) -> bool {
+    let is_arity = |a: &MalVal| match a {
+        List(a, _) => matches!(a.first(), Some(List(..)) | Some(Vector(..))),
+        _ => false,
+    };
+    l.len() >= 2 && l.iter().skip(1).all(is_arity)
+}
+
+// the values a macro is given must look as the user wrote them
+pub fn unresolve(ast: &MalVal) -> MalVal {
+    match ast {
+        Local(_, _, s) => Sym(*s),
+        List(..) | Vector(..) | Hash(..) => map_forms(ast, &mut unresolve),
+        _ => ast.clone(),
+    }
+}
+
+// the slots env_bind fills for these parameters, in order
+fn param_slots(params: &MalVal) -> Option<Vec<Symbol>> {
+    let binds = match params {
+        List(binds, _) | Vector(binds, _) => binds,
+        _ => return None,
+    };
+    let mut slots = vec![];
+    let mut it = binds.iter();
+    while let Some(b) = it.next() {
+        match b {
+            Sym(s) if s == "&" => match it.next() {
+                Some(Sym(rest)) => {
+                    slots.push(*rest);
+                    break;
+                }
+                _ => return None,
+            },
+            Sym(s) => slots.push(*s),
+            _ => return None,
+        }
+    }
+    Some(slots)
+}
+
+fn head(l: &PVec<MalVal>) -> Option<&'static str> {
+    match l.first() {
+        Some(Sym(s)) => Some(s.name()),
+        _ => None,
+    }
+}
+
+// rebuilds a list, vector or map (values only), keeping its metadata
+fn map_forms(ast: &MalVal, f: &mut dyn FnMut(&MalVal) -> MalVal) -> MalVal {
+    match ast {
+        List(l, m) => List(Rc::new(l.iter().map(&mut *f).collect()), m.clone()),
+        Vector(v, m) => Vector(Rc::new(v.iter().map(&mut *f).collect()), m.clone()),
+        Hash(hm, m) => {
+            let mut new_hm = MalMap::new();
+            for (k, v) in hm.iter() {
+                new_hm.insert(k.clone(), f(v));
+            }
+            Hash(Rc::new(new_hm), m.clone())
+        }
+        _ => ast.clone(),
+    }
+}
+
+// the list with the elements from `from` on replaced by f of them
+fn map_tail(
+    l: &PVec<MalVal>,
+    m: &Rc<MalVal>,
+    from: usize,
+    f: &mut dyn FnMut(&MalVal) -> MalVal,
+) -> MalVal {
+    let items = l
+        .iter()
+        .enumerate()
+        .map(|(i, x)| if i < from { x.clone() } else { f(x) });
+    List(Rc::new(items.collect()), m.clone())
+}
+
+// a clause of try*, after its body
+pub enum Clause<'a> {
+    // (catch* e handler), or (catch* selector e handler) where the
+    // selector is a :type keyword or a predicate on the error's map
+    Catch(Option<&'a MalVal>, &'a MalVal, &'a MalVal),
+    // (finally* forms...)
+    Finally(Vec<MalVal>),
+    Invalid,
+}
+
+pub fn try_clause(c: &MalVal) -> Clause<'_> {
+    let c = match c {
+        List(c, _) => c,
+        _ => return Clause::Invalid,
+    };
+    match (head(c), c.len()) {
+        (Some("catch*"), 3) => Clause::Catch(None, &c[1], &c[2]),
+        (Some("catch*"), 4) => Clause::Catch(Some(&c[1]), &c[2], &c[3]),
+        (Some("finally*"), _) => Clause::Finally(c.iter().skip(1).cloned().collect()),
+        _ => Clause::Invalid,
+    }
+}
+
+// rebuilds a try* clause: f is given a catch* handler with the name it
+// binds, and the selector and finally* forms, evaluated outside, alone
+fn map_clause(c: &MalVal, f: &mut dyn FnMut(&MalVal, Option<Symbol>) -> MalVal) -> MalVal {
+    let (l, m) = match c {
+        List(l, m) => (l, m),
+        _ => return c.clone(),
+    };
+    let (sel, bound) = match try_clause(c) {
+        Clause::Catch(sel, Sym(e), _) => (sel.is_some(), Some(*e)),
+        Clause::Catch(sel, _, _) => (sel.is_some(), None),
+        Clause::Finally(_) => return map_tail(l, m, 1, &mut |x| f(x, None)),
+        Clause::Invalid => return c.clone(),
+    };
+    let last = l.len() - 1;
+    let items = l.iter().enumerate().map(|(i, x)| match bound {
+        Some(e) if i == last => f(x, Some(e)),
+        _ if i == 1 && sel => f(x, None),
+        _ => x.clone(),
+    });
+    List(Rc::new(items.collect()), m.clone())
+}
+
+// applies f to the parts of a quasiquote template that get evaluated,
+// following the rules of quasiquote/qq_iter
+fn map_unquoted(ast: &MalVal, f: &mut dyn FnMut(&MalVal) -> MalVal) -> MalVal {
+    let elts = match ast {
+        List(l, m) if l.len() == 2 && head(l) == Some("unquote") => {
+            return map_tail(l, m, 1, f);
+        }
+        List(l, _) | Vector(l, _) => l,
+        _ => return ast.clone(),
+    };
+    let mut each = |elt: &MalVal| match elt {
+        List(l, m) if l.len() == 2 && head(l) == Some("splice-unquote") => map_tail(l, m, 1, f),
+        _ => map_unquoted(elt, f),
+    };
+    match ast {
+        List(_, m) => List(Rc::new(elts.iter().map(&mut each).collect()), m.clone()),
+        Vector(_, m) => Vector(Rc::new(elts.iter().map(&mut each).collect()), m.clone()),
+        _ => unreachable!(),
+    }
+}
+
+fn macro_named(env: &Env, s: Symbol) -> Option<MalVal> {
+    match env_get(env, &Sym(s)) {
+        Ok(f @ MalFunc { is_macro: true, .. }) => Some(f),
+        _ => None,
+    }
+}
+
+fn same_macro(a: &Option<MalVal>, b: &Option<MalVal>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (
+            Some(MalFunc {
+                ast, env, params, ..
+            }),
+            Some(MalFunc {
+                ast: ast2,
+                env: env2,
+                params: params2,
+                ..
+            }),
+        ) => Rc::ptr_eq(ast, ast2) && Rc::ptr_eq(env, env2) && Rc::ptr_eq(params, params2),
+        _ => false,
+    }
+}
+
+fn macro_call(
+    ast: &MalVal,
+    macros: &mut Macros,
+    locals: &[Symbol],
+) -> Option<(MalVal, Vec<MalVal>)> {
+    match ast {
+        List(l, _) => match l.first() {
+            Some(Sym(s)) if !locals.contains(s) => {
+                let mac = macro_named(macros.env, *s);
+                if !macros.seen.iter().any(|(seen, _)| seen == s) {
+                    macros.seen.push((*s, mac.clone()));
+                }
+                mac.map(|f| (f, l.slice(1).to_vec()))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// pass 1: expand every macro call the walker would meet, except that a
+// call which fails to expand is kept for the walker to report
+fn expand(ast: &MalVal, macros: &mut Macros, locals: &mut Vec<Symbol>) -> MalVal {
+    let mut ast = ast.clone();
+    while let Some((mf, args)) = macro_call(&ast, macros, locals) {
+        match mf.apply(args) {
+            Ok(new_ast) => ast = new_ast,
+            Err(_) => return ast,
+        }
+    }
+    if let Ok(Some(form)) = destructure::rewrite(&ast) {
+        return expand(&form, macros, locals);
+    }
+    let (l, m) = match &ast {
+        List(l, m) if !l.is_empty() => (l, m),
+        List(..) => return ast.clone(),
+        _ => return map_forms(&ast, &mut |x| expand(x, macros, locals)),
+    };
+    let depth = locals.len();
+    let new_ast = match head(l) {
+        Some("quote") | Some("quasiquoteexpand") | Some("macroexpand") => ast.clone(),
+        Some("quasiquote") => map_tail(l, m, 1, &mut |x| {
+            map_unquoted(x, &mut |y| expand(y, macros, locals))
+        }),
+        Some("def!") | Some("defmacro!") => map_tail(l, m, 2, &mut |x| expand(x, macros, locals)),
+        Some("let*") | Some("loop*") if l.len() >= 2 => {
+            let binds = match &l[1] {
+                List(binds, _) | Vector(binds, _) => binds,
+                _ => return ast.clone(),
+            };
+            let mut new_binds = vec![];
+            for (i, b) in binds.iter().enumerate() {
+                if i % 2 == 0 {
+                    new_binds.push(b.clone());
+                } else {
+                    new_binds.push(expand(b, macros, locals));
+                    if let Sym(s) = binds[i - 1] {
+                        locals.push(s);
+                    }
+                }
+            }
+            let new_binds = match &l[1] {
+                List(_, bm) => List(Rc::new(new_binds.into()), bm.clone()),
+                Vector(_, bm) => Vector(Rc::new(new_binds.into()), bm.clone()),
+                _ => unreachable!(),
+            };
+            let mut items = vec![l[0].clone(), new_binds];
+            items.extend(l.iter().skip(2).map(|x| expand(x, macros, locals)));
+            List(Rc::new(items.into()), m.clone())
+        }
+        Some("fn*") if fn_arities(l).is_some() => map_tail(l, m, 1, &mut |a| match a {
+            List(a, am) => {
+                let depth = locals.len();
+                locals.extend(param_slots(&a[0]).unwrap_or_default());
+                let a = map_tail(a, am, 1, &mut |x| expand(x, macros, locals));
+                locals.truncate(depth);
+                a
+            }
+            _ => a.clone(),
+        }),
+        Some("fn*") if l.len() >= 2 => {
+            if let List(binds, _) | Vector(binds, _) = &l[1] {
+                for b in binds.iter() {
+                    if let Sym(s) = b {
+                        locals.push(*s);
+                    }
+                }
+            }
+            map_tail(l, m, 2, &mut |x| expand(x, macros, locals))
+        }
+        Some("try*") if l.len() >= 3 => {
+            let mut items = vec![l[0].clone(), expand(&l[1], macros, locals)];
+            for c in l.iter().skip(2) {
+                items.push(map_clause(c, &mut |x, e| {
+                    let depth = locals.len();
+                    locals.extend(e);
+                    let x = expand(x, macros, locals);
+                    locals.truncate(depth);
+                    x
+                }));
+            }
+            List(Rc::new(items.into()), m.clone())
+        }
+        _ => map_tail(l, m, 0, &mut |x| expand(x, macros, locals)),
+    };
+    locals.truncate(depth);
+    new_ast
+}
+
+// pass 2: the names def!'d in a scope, not counting nested ones
+fn collect_defs(ast: &MalVal, defs: &mut FnvHashSet<Symbol>) {
+    let l = match ast {
+        List(l, _) => l,
+        Vector(v, _) => return v.iter().for_each(|x| collect_defs(x, defs)),
+        Hash(hm, _) => return hm.values().for_each(|x| collect_defs(x, defs)),
+        _ => return,
+    };
+    match head(l) {
+        Some("quote") | Some("quasiquoteexpand") | Some("macroexpand") => (),
+        Some("let*") | Some("loop*") | Some("fn*") => (),
+        Some("quasiquote") => {
+            for x in l.iter().skip(1) {
+                map_unquoted(x, &mut |y| {
+                    collect_defs(y, defs);
+                    y.clone()
+                });
+            }
+        }
+        Some("def!") | Some("defmacro!") => {
+            if let Some(Sym(s)) = l.get(1) {
+                defs.insert(*s);
+            }
+            l.iter().skip(2).for_each(|x| collect_defs(x, defs));
+        }
+        // each handler runs in its own scope
+        Some("try*") => {
+            l.iter().skip(1).take(1).for_each(|x| collect_defs(x, defs));
+            for c in l.iter().skip(2) {
+                map_clause(c, &mut |x, e| {
+                    if e.is_none() {
+                        collect_defs(x, defs);
+                    }
+                    x.clone()
+                });
+            }
+        }
+        _ => l.iter().for_each(|x| collect_defs(x, defs)),
+    }
+}
+
+fn lookup(s: Symbol, scopes: &[Scope]) -> MalVal {
+    for (depth, scope) in scopes.iter().rev().enumerate() {
+        if let Some(slot) = scope.slots.iter().rposition(|n| *n == s) {
+            return Local(depth, slot, s);
+        }
+        if scope.defs.contains(&s) {
+            break;
+        }
+    }
+    Sym(s)
+}
+
+// resolves forms evaluated in a new frame whose slots start as given
+fn resolve_scope(slots: Vec<Symbol>, forms: &[MalVal], scopes: &mut Vec<Scope>) -> Vec<MalVal> {
+    let mut defs = FnvHashSet::default();
+    forms.iter().for_each(|x| collect_defs(x, &mut defs));
+    scopes.push(Scope { slots, defs });
+    let new_forms = forms.iter().map(|x| resolve(x, scopes)).collect();
+    scopes.pop();
+    new_forms
+}
+
+// pass 3
+fn resolve(ast: &MalVal, scopes: &mut Vec<Scope>) -> MalVal {
+    let (l, m) = match ast {
+        Sym(s) => return lookup(*s, scopes),
+        List(l, m) if !l.is_empty() => (l, m),
+        List(..) => return ast.clone(),
+        _ => return map_forms(ast, &mut |x| resolve(x, scopes)),
+    };
+    match head(l) {
+        Some("quote") | Some("quasiquoteexpand") | Some("macroexpand") => ast.clone(),
+        Some("quasiquote") => map_tail(l, m, 1, &mut |x| {
+            map_unquoted(x, &mut |y| resolve(y, scopes))
+        }),
+        Some("def!") | Some("defmacro!") => map_tail(l, m, 2, &mut |x| resolve(x, scopes)),
+        Some("let*") | Some("loop*") if l.len() >= 2 => {
+            resolve_let(l, m, scopes).unwrap_or_else(|| ast.clone())
+        }
+        Some("fn*") if fn_arities(l).is_some() => map_tail(l, m, 1, &mut |a| match a {
+            List(a, am) => match param_slots(&a[0]) {
+                Some(slots) => {
+                    let body = resolve_scope(slots, &a.slice(1).to_vec(), scopes);
+                    List(
+                        Rc::new([vec![a[0].clone()], body].concat().into()),
+                        am.clone(),
+                    )
+                }
+                None => List(a.clone(), am.clone()),
+            },
+            _ => a.clone(),
+        }),
+        Some("fn*") if l.len() >= 2 => match param_slots(&l[1]) {
+            Some(slots) => {
+                let body: Vec<MalVal> = l.iter().skip(2).cloned().collect();
+                let mut items = vec![l[0].clone(), l[1].clone()];
+                items.extend(resolve_scope(slots, &body, scopes));
+                List(Rc::new(items.into()), m.clone())
+            }
+            None => ast.clone(),
+        },
+        Some("try*") if l.len() >= 3 => {
+            let mut items = vec![l[0].clone(), resolve(&l[1], scopes)];
+            for c in l.iter().skip(2) {
+                items.push(map_clause(c, &mut |x, e| match e {
+                    Some(e) => resolve_scope(vec![e], std::slice::from_ref(x), scopes)
+                        .pop()
+                        .unwrap(),
+                    None => resolve(x, scopes),
+                }));
+            }
+            List(Rc::new(items.into()), m.clone())
+        }
+        Some(s) if SPECIAL_FORMS.contains(&s) => map_tail(l, m, 1, &mut |x| resolve(x, scopes)),
+        _ => map_tail(l, m, 0, &mut |x| resolve(x, scopes)),
+    }
+}
+
+// a let* frame gets a slot per distinct name, in order of first binding,
+// and each binding only sees the ones before it
+fn resolve_let(l: &PVec<MalVal>, m: &Rc<MalVal>, scopes: &mut Vec<Scope>) -> Option<MalVal> {
+    let binds = match &l[1] {
+        List(binds, _) | Vector(binds, _) => binds,
+        _ => return None,
+    };
+    if binds.iter().step_by(2).any(|b| !matches!(b, Sym(_))) {
+        return None;
+    }
+    let mut defs = FnvHashSet::default();
+    binds
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .for_each(|x| collect_defs(x, &mut defs));
+    l.iter().skip(2).for_each(|x| collect_defs(x, &mut defs));
+    scopes.push(Scope {
+        slots: vec![],
+        defs,
+    });
+
+    let mut new_binds = vec![];
+    for (i, b) in binds.iter().enumerate() {
+        match (i % 2, binds.get(i + 1)) {
+            (0, Some(_)) => (),
+            // a trailing name without a value is ignored by let*
+            (0, None) => new_binds.push(b.clone()),
+            _ => {
+                new_binds.push(resolve(b, scopes));
+                if let Sym(s) = binds[i - 1] {
+                    let scope = scopes.last_mut().unwrap();
+                    let slot = match scope.slots.iter().position(|n| *n == s) {
+                        Some(slot) => slot,
+                        None => {
+                            scope.slots.push(s);
+                            scope.slots.len() - 1
+                        }
+                    };
+                    let n = new_binds.len();
+                    new_binds.insert(n - 1, Local(0, slot, s));
+                }
+            }
+        }
+    }
+    let new_binds = match &l[1] {
+        List(_, bm) => List(Rc::new(new_binds.into()), bm.clone()),
+        Vector(_, bm) => Vector(Rc::new(new_binds.into()), bm.clone()),
+        _ => unreachable!(),
+    };
+    let mut items = vec![l[0].clone(), new_binds];
+    items.extend(l.iter().skip(2).map(|x| resolve(x, scopes)));
+    scopes.pop();
+    Some(List(Rc::new(items.into()), m.clone()))
+}
@@ -1,5 +1,3 @@
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
 extern crate regex;
@@ -8,11 +6,18 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
 #[allow(dead_code)]
+mod bigint;
+#[allow(dead_code)]
+mod pmap;
+#[allow(dead_code)]
+mod pvec;
+#[macro_use]
+#[allow(dead_code, unused_macros)]
 mod types;
 use crate::types::format_error;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -1,10 +1,7 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
 extern crate regex;
@@ -13,13 +10,21 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+#[allow(dead_code)]
+mod bigint;
+#[allow(dead_code)]
+mod pmap;
+#[allow(dead_code)]
+mod pvec;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::{error, format_error, func, MalArgs, MalErr, MalMap, MalRet, MalVal};
+#[allow(dead_code)]
 mod env;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 
@@ -47,9 +52,9 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut new_hm: MalMap = MalMap::new();
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                new_hm.insert(k.clone(), eval(v.clone(), env.clone())?);
             }
             Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
         }
@@ -97,7 +102,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.slice(1).to_vec())
                     }
                     _ => error("expected a list"),
                 },
@@ -1,29 +1,43 @@
 #![allow(non_snake_case)]
 
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
 extern crate itertools;
+extern crate libc;
 extern crate regex;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+mod bigint;
+mod bodies;
+mod pmap;
+mod pvec;
+use crate::bodies::BodyCache;
+use crate::pvec::PVec;
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, Keyword, List, Local, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::{error, sym, MalArgs, MalErr, MalMap, MalRet, MalVal};
+mod destructure;
 mod env;
+mod limits;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+mod resolve;
+mod trace;
+mod vm;
+use crate::env::{env_bind, env_find, env_get, env_get_slot, env_new, env_set, env_sets, Env};
+use crate::limits::{Budget, Depth};
 #[macro_use]
 mod core;
 
@@ -34,20 +48,20 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &PVec<MalVal>) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![sym("concat"), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![sym("cons"), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -63,9 +77,9 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                 }
             }
             return qq_iter(&v);
-        },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        }
+        Vector(v, _) => return list![sym("vec"), qq_iter(&v)],
+        Hash(_, _) | Sym(_) => return list![sym("quote"), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -73,9 +87,9 @@ fn quasiquote(ast: &MalVal) -> MalVal {
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
         List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
+            Sym(ref s) => match env_find(env, *s) {
                 Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v.slice(1).to_vec())),
                     _ => None,
                 },
                 _ => None,
@@ -100,218 +114,534 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     ((was_expanded, Ok(ast)))
 }
 
-fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
-    match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
-        List(v, _) => {
-            let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+// Forms are analyzed once into Code: closures with the special form
+// already decided and constants already built, which are then run
+// against an environment as often as needed. Code in tail position
+// hands back the call (or eval) it ends in, for `run` to carry on with
+// in a loop, so tail calls do not grow the Rust stack.
+type Code = Rc<dyn Fn(&Env) -> Result<Next, MalErr>>;
+
+enum Next {
+    Value(MalVal),
+    Call(MalVal, MalArgs),
+    Eval(MalVal, Env),
+    // only ever handed back to the enclosing loop*
+    Recur(MalArgs),
+}
+
+// where a form sits: whether it may hand back a tail call, and what a
+// recur there would go back to
+#[derive(Clone, Copy)]
+struct Pos {
+    tail: bool,
+    recur: Recur,
+}
+
+#[derive(Clone, Copy)]
+enum Recur {
+    Outside,
+    NotTail,
+    // a loop* with this many bindings
+    Loop(usize),
+}
+
+const TOP: Pos = Pos {
+    tail: true,
+    recur: Recur::Outside,
+};
+
+impl Pos {
+    // an operand, evaluated before the form it is in
+    fn inner(self) -> Pos {
+        Pos {
+            tail: false,
+            recur: match self.recur {
+                Recur::Outside => Recur::Outside,
+                _ => Recur::NotTail,
+            },
+        }
+    }
+}
+
+fn run(next: Next) -> MalRet {
+    let mut func = None;
+    run_in(next, &mut func).map_err(|e| match func {
+        Some((ast, meta)) => trace::leave(e, &ast, &meta),
+        None => e,
+    })
+}
+
+// `func` follows the mal function whose body is running, which is the
+// frame an error passing out of here leaves
+fn run_in(mut next: Next, func: &mut Option<(Rc<MalVal>, Rc<MalVal>)>) -> MalRet {
+    loop {
+        next = match next {
+            Next::Value(v) => return Ok(v),
+            Next::Call(
+                MalFunc {
+                    ast,
+                    env,
+                    params,
+                    meta,
+                    ..
+                },
+                args,
+            ) => {
+                limits::tick()?;
+                let (params, body) = env::select_arity(&params, &ast, args.len())
+                    .map_err(|e| trace::calling(e, &ast, &meta))?;
+                let env = env_bind(Some(env), params.clone(), args)
+                    .map_err(|e| trace::calling(e, &ast, &meta))?;
+                let code = body_code(body)?;
+                *func = Some((ast, meta));
+                code(&env)?
+            }
+            Next::Call(f, args) => {
+                limits::tick()?;
+                return f.apply(args);
+            }
+            Next::Eval(ast, env) => {
+                limits::tick()?;
+                analyze(&ast, false, TOP)?(&env)?
             }
-            Ok(list!(lst))
+            Next::Recur(_) => unreachable!("recur is checked to be in a loop* tail"),
         }
-        Vector(v, _) => {
-            let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+    }
+}
+
+// each Rust call into `run` is a level of recursion, and counts
+// towards the depth limit
+fn exec(code: &Code, env: &Env) -> MalRet {
+    match code(env)? {
+        Next::Value(v) => Ok(v),
+        next => {
+            let _depth = Depth::enter()?;
+            run(next)
+        }
+    }
+}
+
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    exec(&analyze(&ast, false, TOP)?, &env)
+}
+
+// the body of a MalFunc, which went through the resolver when the
+// closure was created; called from Rust (a builtin such as map, apply
+// or swap!), so it is a level of recursion too
+fn eval_body(ast: MalVal, env: Env) -> MalRet {
+    limits::tick()?;
+    let _depth = Depth::enter()?;
+    exec(&body_code(&ast)?, &env).map_err(|e| trace::leave(e, &ast, &Nil))
+}
+
+thread_local! {
+    static BODIES: RefCell<BodyCache<Code>> = RefCell::new(BodyCache::new());
+}
+
+fn body_code(body: &MalVal) -> Result<Code, MalErr> {
+    if let Some(code) = BODIES.with(|b| b.borrow().get(body)) {
+        return Ok(code);
+    }
+    let code = analyze(body, true, TOP)?;
+    BODIES.with(|b| b.borrow_mut().insert(body, code.clone()));
+    Ok(code)
+}
+
+fn constant(val: MalVal) -> Code {
+    Rc::new(move |_| Ok(Next::Value(val.clone())))
+}
+
+fn failing(msg: &'static str) -> Code {
+    Rc::new(move |_| error(msg).map(Next::Value))
+}
+
+// which errors a catch* clause handles
+enum Select {
+    All,
+    // by :type, or any error for :default
+    Type(MalVal),
+    Pred(Code),
+    Invalid,
+}
+
+// the value of the first catch* clause to handle the error, which a
+// bare clause sees as thrown and the others as a map
+fn catch(clauses: &[(Select, MalVal, Code)], e: MalErr, env: &Env) -> MalRet {
+    for (sel, exc, handler) in clauses {
+        let val = match sel {
+            Select::All => e.value(),
+            Select::Type(t) if *t == types::keyword("default") || *t == e.kind() => e.to_map(),
+            Select::Type(_) => continue,
+            Select::Pred(pred) => match exec(pred, env)?.apply(vec![e.to_map()])? {
+                Bool(false) | Nil => continue,
+                _ => e.to_map(),
+            },
+            Select::Invalid => return error("invalid catch block"),
+        };
+        trace::caught(&e);
+        let catch_env = env_bind(Some(env.clone()), list!(vec![exc.clone()]), vec![val])?;
+        return exec(handler, &catch_env);
+    }
+    Err(e)
+}
+
+// `resolved` is set for resolver output (fn* bodies), whose macro calls
+// are already expanded and whose locals are Local slot references
+fn analyze(ast: &MalVal, resolved: bool, pos: Pos) -> Result<Code, MalErr> {
+    let inner = pos.inner();
+    Ok(match ast {
+        Sym(_) => {
+            let key = ast.clone();
+            Rc::new(move |env| Ok(Next::Value(env_get(env, &key)?)))
+        }
+        Local(depth, slot, _) => {
+            let (depth, slot) = (*depth, *slot);
+            Rc::new(move |env| Ok(Next::Value(env_get_slot(env, depth, slot))))
+        }
+        List(l, m) if !l.is_empty() => {
+            let code = analyze_list(l, ast, resolved, pos).map_err(|e| e.at(m))?;
+            match **m {
+                // errors are attributed to the innermost form with a location
+                Hash(..) => {
+                    let meta = (**m).clone();
+                    Rc::new(move |env| code(env).map_err(|e| e.at(&meta)))
+                }
+                _ => code,
             }
-            Ok(vector!(lst))
+        }
+        Vector(v, _) => {
+            let items = v
+                .iter()
+                .map(|x| analyze(x, resolved, inner))
+                .collect::<Result<Vec<Code>, MalErr>>()?;
+            Rc::new(move |env| {
+                let mut lst: MalArgs = vec![];
+                for c in items.iter() {
+                    lst.push(exec(c, env)?);
+                }
+                Ok(Next::Value(vector!(lst)))
+            })
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut items: Vec<(MalVal, Code)> = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                items.push((k.clone(), analyze(v, resolved, inner)?));
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Rc::new(move |env| {
+                let mut new_hm: MalMap = MalMap::new();
+                for (k, c) in items.iter() {
+                    new_hm.insert(k.clone(), exec(c, env)?);
+                }
+                Ok(Next::Value(Hash(Rc::new(new_hm), Rc::new(Nil))))
+            })
         }
-        _ => Ok(ast.clone()),
-    }
+        _ => constant(ast.clone()),
+    })
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
-    let ret: MalRet;
+// the bindings of a let* or loop*; a malformed binding vector is only
+// an error when the form is run
+fn analyze_binds(
+    binds: &MalVal,
+    resolved: bool,
+    pos: Pos,
+) -> Result<Result<Vec<(MalVal, Code)>, Code>, MalErr> {
+    let binds = match binds {
+        List(binds, _) | Vector(binds, _) => binds,
+        _ => return Ok(Err(failing("let* with non-List bindings"))),
+    };
+    let mut pairs = vec![];
+    for (b, e) in binds.iter().tuples() {
+        match b {
+            Sym(_) | Local(..) => pairs.push((b.clone(), analyze(e, resolved, pos.inner())?)),
+            _ => return Ok(Err(failing("let* with non-Sym binding"))),
+        }
+    }
+    Ok(Ok(pairs))
+}
 
-    'tco: loop {
-        ret = match ast.clone() {
-            List(l, _) => {
-                if l.len() == 0 {
-                    return Ok(ast);
-                }
-                match macroexpand(ast.clone(), &env) {
-                    (true, Ok(new_ast)) => {
-                        ast = new_ast;
-                        continue 'tco;
+// one env serves every pass through the body unless something captured it
+fn analyze_loop(l: &PVec<MalVal>, resolved: bool, pos: Pos) -> Result<Code, MalErr> {
+    let arg = |i: usize| l.get(i).cloned().unwrap_or(Nil);
+    let pairs = match analyze_binds(&arg(1), resolved, pos)? {
+        Ok(pairs) => pairs,
+        Err(fail) => return Ok(fail),
+    };
+    let body_pos = Pos {
+        tail: pos.tail,
+        recur: Recur::Loop(pairs.len()),
+    };
+    let body = analyze(&arg(2), resolved, body_pos)?;
+    Ok(Rc::new(move |env| {
+        let mut env = env_new(Some(env.clone()));
+        for (b, c) in pairs.iter() {
+            let val = exec(c, &env)?;
+            env_set(&env, b.clone(), val)?;
+        }
+        loop {
+            match body(&env)? {
+                Next::Recur(vals) => {
+                    limits::tick()?;
+                    // the frame is reused unless a closure kept it
+                    if Rc::strong_count(&env) > 1 {
+                        env = env_new(env.outer.clone());
+                    }
+                    for ((b, _), val) in pairs.iter().zip(vals) {
+                        env_set(&env, b.clone(), val)?;
                     }
-                    (_, Err(e)) => return Err(e),
-                    _ => (),
                 }
+                next => return Ok(next),
+            }
+        }
+    }))
+}
 
-                if l.len() == 0 {
-                    return Ok(ast);
+fn analyze_list(l: &PVec<MalVal>, form: &MalVal, resolved: bool, pos: Pos) -> Result<Code, MalErr> {
+    if let Some(form) = destructure::rewrite(form)? {
+        return analyze(&form, resolved, pos);
+    }
+    let a0 = match l[0] {
+        Sym(ref s) => s.name(),
+        _ => "",
+    };
+    let arg = |i: usize| l.get(i).cloned().unwrap_or(Nil);
+    let inner = pos.inner();
+    Ok(match a0 {
+        "def!" => {
+            let (key, val) = (arg(1), analyze(&arg(2), resolved, inner)?);
+            Rc::new(move |env| {
+                let val = exec(&val, env)?;
+                trace::named(&key, &val);
+                Ok(Next::Value(env_set(env, key.clone(), val)?))
+            })
+        }
+        "defmacro!" => {
+            let (key, val) = (arg(1), analyze(&arg(2), resolved, inner)?);
+            Rc::new(move |env| match exec(&val, env)? {
+                MalFunc {
+                    eval,
+                    ast,
+                    env: fenv,
+                    params,
+                    ..
+                } => {
+                    let mac = MalFunc {
+                        eval,
+                        ast,
+                        env: fenv,
+                        params,
+                        is_macro: true,
+                        meta: Rc::new(Nil),
+                    };
+                    trace::named(&key, &mac);
+                    Ok(Next::Value(env_set(env, key.clone(), mac)?))
                 }
-                let a0 = &l[0];
-                match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
-                    }
-                    Sym(ref a0sym) if a0sym == "let*" => {
-                        env = env_new(Some(env.clone()));
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                return error("let* with non-List bindings");
-                            }
+                _ => error("set_macro on non-function").map(Next::Value),
+            })
+        }
+        "let*" => {
+            let pairs = match analyze_binds(&arg(1), resolved, pos)? {
+                Ok(pairs) => pairs,
+                Err(fail) => return Ok(fail),
+            };
+            let body = analyze(&arg(2), resolved, pos)?;
+            Rc::new(move |env| {
+                let env = env_new(Some(env.clone()));
+                for (b, c) in pairs.iter() {
+                    let val = exec(c, &env)?;
+                    env_set(&env, b.clone(), val)?;
+                }
+                body(&env)
+            })
+        }
+        // like let*, but a recur in tail position of the body evaluates
+        // to new values for the bindings and goes round again
+        "loop*" if resolved => analyze_loop(l, resolved, pos)?,
+        "loop*" => {
+            // macros are expanded first, so the recurs they make are seen
+            let form = form.clone();
+            Rc::new(move |env| match resolve::expand_macros(&form, env) {
+                List(l, _) => analyze_loop(&l, false, pos)?(env),
+                _ => unreachable!(),
+            })
+        }
+        "recur" => {
+            let n = match pos.recur {
+                Recur::Loop(n) => n,
+                Recur::NotTail => return Err(ErrString("recur not in tail position".to_string())),
+                Recur::Outside => return Err(ErrString("recur outside of loop*".to_string())),
+            };
+            if l.len() - 1 != n {
+                let msg = format!("recur: expected {} args, got {}", n, l.len() - 1);
+                return Err(ErrString(msg));
+            }
+            let args = l
+                .iter()
+                .skip(1)
+                .map(|x| analyze(x, resolved, inner))
+                .collect::<Result<Vec<Code>, MalErr>>()?;
+            Rc::new(move |env| {
+                let mut vals: MalArgs = Vec::with_capacity(args.len());
+                for c in args.iter() {
+                    vals.push(exec(c, env)?);
+                }
+                Ok(Next::Recur(vals))
+            })
+        }
+        "quote" => constant(arg(1)),
+        "quasiquoteexpand" => constant(quasiquote(&arg(1))),
+        "quasiquote" => analyze(&quasiquote(&arg(1)), resolved, pos)?,
+        "macroexpand" => {
+            let ast = arg(1);
+            Rc::new(move |env| Ok(Next::Value(macroexpand(ast.clone(), env).1?)))
+        }
+        "try*" => {
+            let body = analyze(&arg(1), resolved, inner)?;
+            let mut clauses = vec![];
+            let mut finally = None;
+            for (i, c) in l.iter().enumerate().skip(2) {
+                match resolve::try_clause(c) {
+                    resolve::Clause::Catch(sel, exc, handler) => {
+                        let sel = match sel {
+                            None => Select::All,
+                            Some(t @ Keyword(_)) => Select::Type(t.clone()),
+                            Some(pred) => Select::Pred(analyze(pred, resolved, inner)?),
                         };
-                        ast = a2;
-                        continue 'tco;
-                    }
-                    Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
-                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
-                    Sym(ref a0sym) if a0sym == "quasiquote" => {
-                        ast = quasiquote(&l[1]);
-                        continue 'tco;
-                    }
-                    Sym(ref a0sym) if a0sym == "defmacro!" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        let r = eval(a2, env.clone())?;
-                        match r {
-                            MalFunc {
-                                eval,
-                                ast,
-                                env,
-                                params,
-                                ..
-                            } => Ok(env_set(
-                                &env,
-                                a1.clone(),
-                                MalFunc {
-                                    eval: eval,
-                                    ast: ast.clone(),
-                                    env: env.clone(),
-                                    params: params.clone(),
-                                    is_macro: true,
-                                    meta: Rc::new(Nil),
-                                },
-                            )?),
-                            _ => error("set_macro on non-function"),
-                        }
-                    }
-                    Sym(ref a0sym) if a0sym == "macroexpand" => {
-                        match macroexpand(l[1].clone(), &env) {
-                            (_, Ok(new_ast)) => Ok(new_ast),
-                            (_, e) => return e,
-                        }
+                        clauses.push((sel, exc.clone(), analyze(handler, resolved, inner)?));
                     }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
-                                }
-                                _ => error("invalid catch block"),
-                            }
-                        }
-                        res => res,
-                    },
-                    Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
-                            List(_, _) => {
-                                ast = l.last().unwrap_or(&Nil).clone();
-                                continue 'tco;
-                            }
-                            _ => error("invalid do form"),
-                        }
+                    resolve::Clause::Finally(forms) if i == l.len() - 1 => {
+                        let mut form = vec![sym("do")];
+                        form.extend(forms);
+                        finally = Some(analyze(&list!(form), resolved, inner)?);
                     }
-                    Sym(ref a0sym) if a0sym == "if" => {
-                        let cond = eval(l[1].clone(), env.clone())?;
-                        match cond {
-                            Bool(false) | Nil if l.len() >= 4 => {
-                                ast = l[3].clone();
-                                continue 'tco;
-                            }
-                            Bool(false) | Nil => Ok(Nil),
-                            _ if l.len() >= 3 => {
-                                ast = l[2].clone();
-                                continue 'tco;
-                            }
-                            _ => Ok(Nil),
-                        }
-                    }
-                    Sym(ref a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        Ok(MalFunc {
-                            eval: eval,
-                            ast: Rc::new(a2),
-                            env: env,
-                            params: Rc::new(a1),
-                            is_macro: false,
-                            meta: Rc::new(Nil),
-                        })
+                    _ => clauses.push((Select::Invalid, Nil, constant(Nil))),
+                }
+            }
+            if l.len() < 3 {
+                return Ok(body);
+            }
+            Rc::new(move |env| {
+                let ret = match exec(&body, env) {
+                    Err(e) => catch(&clauses, e, env),
+                    ret => ret,
+                };
+                if let Some(ref finally) = finally {
+                    exec(finally, env)?;
+                }
+                ret.map(Next::Value)
+            })
+        }
+        "do" => {
+            let n = l.len() - 1;
+            let init = l
+                .iter()
+                .skip(1)
+                .take(n.saturating_sub(1))
+                .map(|x| analyze(x, resolved, inner))
+                .collect::<Result<Vec<Code>, MalErr>>()?;
+            let last = analyze(&if n > 0 { arg(n) } else { Nil }, resolved, pos)?;
+            Rc::new(move |env| {
+                for c in init.iter() {
+                    exec(c, env)?;
+                }
+                last(env)
+            })
+        }
+        "if" => {
+            let cond = analyze(&arg(1), resolved, inner)?;
+            let (then, els) = (
+                analyze(&arg(2), resolved, pos)?,
+                analyze(&arg(3), resolved, pos)?,
+            );
+            Rc::new(move |env| match exec(&cond, env)? {
+                Bool(false) | Nil => els(env),
+                _ => then(env),
+            })
+        }
+        "fn*" => {
+            let (params, body) = match resolve::fn_arities(l) {
+                Some(arities) => (Nil, arities?),
+                None => {
+                    env::check_params(&arg(1))?;
+                    (arg(1), arg(2))
+                }
+            };
+            let (params, body) = (Rc::new(params), Rc::new(body));
+            Rc::new(move |env| {
+                let ast = if resolved {
+                    body.clone()
+                } else {
+                    resolve::resolve_fn(&params, &body, env)
+                };
+                // analyzed now, so that errors in it show up here
+                for (_, body) in env::arities(&params, &ast) {
+                    body_code(body)?;
+                }
+                Ok(Next::Value(MalFunc {
+                    eval: eval_body,
+                    ast,
+                    env: env.clone(),
+                    params: params.clone(),
+                    is_macro: false,
+                    meta: Rc::new(Nil),
+                }))
+            })
+        }
+        "eval" => {
+            let arg = analyze(&arg(1), resolved, inner)?;
+            Rc::new(move |env| {
+                let ast = exec(&arg, env)?;
+                let mut root = env.clone();
+                while let Some(ref e) = root.clone().outer {
+                    root = e.clone();
+                }
+                Ok(Next::Eval(ast, root))
+            })
+        }
+        _ => {
+            let head = analyze(&l[0], resolved, inner)?;
+            let args = l
+                .iter()
+                .skip(1)
+                .map(|x| analyze(x, resolved, inner))
+                .collect::<Result<Vec<Code>, MalErr>>();
+            // a symbol may name a macro defined after this was analyzed,
+            // whose arguments are forms that need not make sense as code
+            let form = match l[0] {
+                Sym(_) | Local(..) => Some(form.clone()),
+                _ => None,
+            };
+            if form.is_none() {
+                args.as_ref().map_err(|e| e.clone())?;
+            }
+            let tail = pos.tail;
+            Rc::new(move |env| {
+                let f = exec(&head, env)?;
+                if let (MalFunc { is_macro: true, .. }, Some(List(l, _))) = (&f, &form) {
+                    let ast = f.apply(l.iter().skip(1).map(resolve::unresolve).collect())?;
+                    return Ok(Next::Eval(ast, env.clone()));
+                }
+                let args = args.as_ref().map_err(|e| e.clone())?;
+                let mut vals: MalArgs = Vec::with_capacity(args.len());
+                for c in args.iter() {
+                    vals.push(exec(c, env)?);
+                }
+                // only mal calls in tail position go back to `run`; the
+                // rest are made here, so that errors get this form's location
+                match f {
+                    MalFunc { .. } if tail => Ok(Next::Call(f, vals)),
+                    MalFunc { .. } => {
+                        let _depth = Depth::enter()?;
+                        run(Next::Call(f, vals)).map(Next::Value)
                     }
-                    Sym(ref a0sym) if a0sym == "eval" => {
-                        ast = eval(l[1].clone(), env.clone())?;
-                        while let Some(ref e) = env.clone().outer {
-                            env = e.clone();
-                        }
-                        continue 'tco;
+                    _ => {
+                        limits::tick()?;
+                        f.apply(vals).map(Next::Value)
                     }
-                    _ => match eval_ast(&ast, &env)? {
-                        List(ref el, _) => {
-                            let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
-                            match f {
-                                Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
-                                    continue 'tco;
-                                }
-                                _ => error("attempt to call non-function"),
-                            }
-                        }
-                        _ => error("expected a list"),
-                    },
                 }
-            }
-            _ => eval_ast(&ast, &env),
-        };
-
-        break;
-    } // end 'tco loop
-
-    ret
+            })
+        }
+    })
 }
 
 // print
@@ -319,15 +649,110 @@ fn print(ast: &MalVal) -> String {
     ast.pr_str(true)
 }
 
+thread_local! {
+    // set by --vm: evaluate with the bytecode VM instead
+    static USE_VM: Cell<bool> = const { Cell::new(false) };
+    // where load-file evaluates the forms it reads
+    static REPL_ENV: RefCell<Option<Env>> = const { RefCell::new(None) };
+}
+
+fn eval_top(ast: MalVal, env: &Env) -> MalRet {
+    if USE_VM.with(Cell::get) {
+        vm::eval(ast, env.clone())
+    } else {
+        eval(ast, env.clone())
+    }
+}
+
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     let ast = read(str)?;
-    let exp = eval(ast, env.clone())?;
-    Ok(print(&exp))
+    Ok(print(&eval_top(ast, env)?))
+}
+
+// (load-file f): the forms in the file evaluated in turn, as if entered
+// at the REPL; an error leaves it as it would a function, with the frame
+// at the form that failed
+fn load_file(a: MalArgs) -> MalRet {
+    let f = match a[0] {
+        Str(ref f) => f,
+        _ => return error("load-file: expecting string arg"),
+    };
+    let src = match std::fs::read_to_string(f) {
+        Ok(src) => src,
+        Err(e) => return types::typed_error("io-error", &e.to_string()),
+    };
+    let env = match REPL_ENV.with(|e| e.borrow().clone()) {
+        Some(env) => env,
+        None => return error("load-file: no REPL environment"),
+    };
+    for form in reader::read_all(&src, Some(f))? {
+        if let Err(e) = eval_top(form.clone(), &env) {
+            return Err(e.leaving_form(Some(types::Symbol::new("load-file")), &form));
+        }
+    }
+    Ok(Nil)
+}
+
+fn usage(err: &str) -> ! {
+    eprintln!("{}", err);
+    eprintln!("usage: stepA_mal [--vm] [--max-depth N] [--stack-size MB] [--max-steps N] [--timeout MS] [file args...]");
+    std::process::exit(2);
 }
 
 fn main() {
-    let mut args = std::env::args();
-    let arg1 = args.nth(1);
+    let mut args = std::env::args().skip(1).peekable();
+    let (mut use_vm, mut max_depth, mut stack_mb) = (false, None, 256);
+    let (mut max_steps, mut timeout) = (None, None);
+    // options come before the file to run
+    while let Some(opt) = args.peek().cloned() {
+        let mut number = || {
+            args.next();
+            match args.peek().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => n,
+                _ => usage(&format!("{} needs a number", opt)),
+            }
+        };
+        match opt.as_str() {
+            "--vm" => use_vm = true,
+            "--max-depth" => max_depth = Some(number()),
+            "--stack-size" => stack_mb = number(),
+            "--max-steps" => max_steps = Some(number() as u64),
+            "--timeout" => timeout = Some(Duration::from_millis(number() as u64)),
+            _ => break,
+        }
+        args.next();
+    }
+    let args: Vec<String> = args.collect();
+    // a script is given the budget as a whole, each form typed at the
+    // REPL one of its own
+    let budget = move || Budget {
+        steps: max_steps,
+        deadline: timeout.map(|t| Instant::now() + t),
+    };
+
+    // evaluation runs on a thread of its own so that its stack is as big
+    // as the depth limit needs: 16 KiB a level leaves room to spare
+    let stack = stack_mb << 20;
+    let max_depth = max_depth.unwrap_or(stack >> 14);
+    let repl = std::thread::Builder::new()
+        .name("eval".to_string())
+        .stack_size(stack)
+        .spawn(move || {
+            USE_VM.with(|vm| vm.set(use_vm));
+            limits::set_max_depth(max_depth);
+            types::set_calling(trace::calling);
+            repl(args, budget)
+        });
+    match repl.map(|t| t.join()) {
+        Ok(Ok(())) => (),
+        Ok(Err(_)) => std::process::exit(101),
+        Err(e) => usage(&format!("cannot start evaluator thread: {}", e)),
+    }
+}
+
+fn repl(args: Vec<String>, budget: impl Fn() -> Budget) {
+    let mut args = args.into_iter();
+    let arg1 = args.next();
 
     // `()` can be used when no completer is required
     let mut rl = Editor::<()>::new();
@@ -337,47 +762,78 @@ fn main() {
 
     // core.rs: defined using rust
     let repl_env = env_new(None);
+    REPL_ENV.with(|e| *e.borrow_mut() = Some(repl_env.clone()));
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
     env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    for (k, v) in [
+        builtin!(
+            "call-with-budget",
+            [core::MAP_NIL, core::FN],
+            limits::call_with_budget
+        ),
+        builtin!("stack-trace", [], trace::stack_trace),
+        builtin!("load-file", [core::STR], load_file),
+    ] {
+        env_sets(&repl_env, k, v);
+    }
 
     // core.mal: defined using the language itself
     let _ = rep("(def! *host-language* \"rust\")", &repl_env);
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
+    let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
     let _ = rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
+        "(defmacro! loop (fn* (bindings & body) `(loop* ~bindings (do ~@body))))",
         &repl_env,
     );
-    let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
+    let _ = rep("(defmacro! with-budget (fn* (limits & body) `(call-with-budget ~limits (fn* () (do ~@body)))))", &repl_env);
 
     // Invoked with arguments
     if let Some(f) = arg1 {
-        match rep(&format!("(load-file \"{}\")", f), &repl_env) {
+        match limits::with_budget(budget(), || {
+            rep(&format!("(load-file \"{}\")", f), &repl_env)
+        }) {
             Ok(_) => std::process::exit(0),
             Err(e) => {
-                println!("Error: {}", format_error(e));
+                println!("Error: {}", trace::report(e));
                 std::process::exit(1);
             }
         }
     }
 
-    // main repl loop
+    // main repl loop; Ctrl-C while a form is evaluated abandons it
     let _ = rep("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+    limits::catch_interrupts();
+    let mut input = String::new();
     loop {
-        let readline = rl.readline("user> ");
+        let prompt = if input.is_empty() { "user> " } else { "  ... " };
+        let readline = rl.readline(prompt);
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(line.trim_end_matches(&['\n', '\r'][..]));
+                // keep reading until the form is complete
+                if reader::incomplete(&input) {
+                    continue;
+                }
+                let entry = std::mem::take(&mut input);
+                rl.add_history_entry(&entry);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
-                    match rep(&line, &repl_env) {
+                if !entry.is_empty() {
+                    limits::clear_interrupt();
+                    match limits::with_budget(budget(), || rep(&entry, &repl_env)) {
                         Ok(out) => println!("{}", out),
-                        Err(e) => println!("Error: {}", format_error(e)),
+                        Err(e) => println!("Error: {}", trace::report(e)),
                     }
                 }
             }
-            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
             Err(ReadlineError::Eof) => break,
             Err(err) => {
                 println!("Error: {:?}", err);
@@ -386,3 +842,33 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    // what SIGINT does to a loop that is running, in either evaluator;
+    // the flag is shared, so the two run one after the other
+    #[test]
+    fn interrupt_stops_a_running_loop() {
+        for vm in [false, true] {
+            USE_VM.with(|v| v.set(vm));
+            let env = env_new(None);
+            for (k, v) in core::ns() {
+                env_sets(&env, k, v);
+            }
+            limits::clear_interrupt();
+            let signal = thread::spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                limits::interrupt();
+            });
+            let out = rep("(try* (loop* [i 0] (recur (+ i 1))) (catch* e e))", &env);
+            signal.join().unwrap();
+            limits::clear_interrupt();
+            let out = out.map_err(trace::report);
+            assert_eq!(out, Ok("\"interrupted\"".to_string()), "vm: {}", vm);
+        }
+    }
+}
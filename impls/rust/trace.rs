@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::bodies::BodyCache;
+use crate::env::arities;
+use crate::types::MalErr::ErrTyped;
+use crate::types::MalVal::{Hash, List, Local, MalFunc, Nil, Str, Sym};
+use crate::types::{format_error, hash_map, keyword, Loc, MalArgs, MalErr, MalRet, MalVal, Symbol};
+
+// Mal-level stack traces. An error collects a frame (see MalErr::leaving)
+// for each mal function it passes out of on its way to a catch* or to
+// the REPL, so nothing is kept while evaluation goes well. Functions are
+// named by their :name metadata or by the def! that bound them.
+
+type Frames = Vec<(Option<Symbol>, Option<Loc>)>;
+
+thread_local! {
+    static NAMES: RefCell<BodyCache<Symbol>> = RefCell::new(BodyCache::new());
+    static CAUGHT: RefCell<Frames> = const { RefCell::new(vec![]) };
+}
+
+// def! names the function it binds, unless it is already named
+pub fn named(key: &MalVal, val: &MalVal) {
+    let name = match key {
+        Sym(s) | Local(_, _, s) => *s,
+        _ => return,
+    };
+    if let MalFunc { ast, params, .. } = val {
+        // so are the arities of a multi-arity fn, for eval_body
+        let mut bodies = vec![&**ast];
+        if let Nil = **params {
+            bodies.extend(arities(params, ast).into_iter().map(|(_, body)| body));
+        }
+        for body in bodies {
+            NAMES.with(|n| {
+                if n.borrow().get(body).is_none() {
+                    n.borrow_mut().insert(body, name);
+                }
+            });
+        }
+    }
+}
+
+fn name_of(ast: &MalVal, meta: &MalVal) -> Option<Symbol> {
+    if let Hash(hm, _) = meta {
+        match hm.get(&keyword("name")) {
+            Some(Str(s)) => return Some(Symbol::new(s)),
+            Some(Sym(s)) => return Some(*s),
+            _ => (),
+        }
+    }
+    NAMES.with(|n| n.borrow().get(ast))
+}
+
+// the error as it leaves the function with this body and metadata
+pub fn leave(e: MalErr, ast: &MalVal, meta: &MalVal) -> MalErr {
+    e.leaving(name_of(ast, meta))
+}
+
+// an arity error from binding the args of a call names the function
+pub fn calling(e: MalErr, ast: &MalVal, meta: &MalVal) -> MalErr {
+    match e {
+        ErrTyped(kind, msg) if kind == "arity-error" => {
+            let name = name_of(ast, meta).map_or("anonymous fn", |s| s.name());
+            ErrTyped(kind, format!("{}: {}", name, msg))
+        }
+        e => e,
+    }
+}
+
+// catch* keeps the trace of the error it handles for stack-trace
+pub fn caught(e: &MalErr) {
+    CAUGHT.with(|c| *c.borrow_mut() = e.trace().to_vec());
+}
+
+// (stack-trace): the frames of the error last caught, innermost first,
+// as maps of :fn and the :file, :line and :column in it
+pub fn stack_trace(_: MalArgs) -> MalRet {
+    let frames = CAUGHT.with(|c| c.borrow().clone());
+    let mut res = vec![];
+    for (name, loc) in frames {
+        let mut kvs = vec![keyword("fn"), name.map_or(Nil, |s| Str(s.to_string()))];
+        if let Some(Hash(hm, _)) = loc.map(|l| l.to_meta()) {
+            for (k, v) in hm.iter() {
+                kvs.push(k.clone());
+                kvs.push(v.clone());
+            }
+        }
+        res.push(hash_map(kvs)?);
+    }
+    Ok(list!(res))
+}
+
+// a long trace, from running out of stack say, is cut in the middle
+const SHOWN: usize = 20;
+
+// an uncaught error, with a line for each frame of its trace
+pub fn report(e: MalErr) -> String {
+    let frames = e.trace().to_vec();
+    let mut out = format_error(e);
+    for (i, (name, loc)) in frames.iter().enumerate() {
+        if frames.len() > SHOWN && i >= SHOWN / 2 && i < frames.len() - SHOWN / 2 {
+            if i == SHOWN / 2 {
+                out.push_str(&format!("\n  ... {} more", frames.len() - SHOWN));
+            }
+            continue;
+        }
+        out.push_str("\n  in ");
+        match name {
+            Some(name) => out.push_str(name),
+            None => out.push_str("anonymous fn"),
+        }
+        if let Some(loc) = loc {
+            out.push_str(&format!(" at {}", loc));
+        }
+    }
+    out
+}
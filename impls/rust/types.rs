@@ -1,24 +1,40 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::hash::Hasher;
+use std::ops::Deref;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet, FnvHasher};
 use itertools::Itertools;
 
-use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::bigint::BigInt;
+use crate::env::{env_bind, select_arity, Env};
+use crate::pmap::PMap;
+use crate::pvec::PVec;
+use crate::types::MalErr::{ErrAt, ErrMalVal, ErrString, ErrTrace, ErrTyped};
+use crate::types::MalVal::{
+    Atom, Big, Bool, Float, Func, Hash, Int, Keyword, List, Local, MalFunc, Nil, Regex, Str, Sym,
+    Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    Big(Rc<BigInt>),
+    Float(f64),
     Str(String),
-    Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
+    Sym(Symbol),
+    Keyword(Rc<str>),
+    // a compiled #"..." pattern, shared by every use of its source
+    Regex(Rc<MalRegex>),
+    // a local variable reference, rewritten by the resolver to a slot
+    // `depth` frames up; only ever found inside fn* bodies
+    Local(usize, usize, Symbol),
+    List(Rc<PVec<MalVal>>, Rc<MalVal>),
+    Vector(Rc<PVec<MalVal>>, Rc<MalVal>),
+    Hash(Rc<MalMap>, Rc<MalVal>),
     Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
@@ -31,12 +47,34 @@ pub enum MalVal {
     Atom(Rc<RefCell<MalVal>>),
 }
 
-#[derive(Debug)]
+// an interned symbol: compared and hashed by id, names live forever
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    id: u32,
+    name: &'static str,
+}
+
+#[derive(Debug, Clone)]
 pub enum MalErr {
     ErrString(String),
+    // an internal error of a more specific :type than :error
+    ErrTyped(&'static str, String),
     ErrMalVal(MalVal),
+    ErrAt(Box<MalErr>, Loc),
+    // the mal functions the error has passed out of, innermost first,
+    // by name (None if anonymous) and where in each it came from
+    ErrTrace(Box<MalErr>, Vec<(Option<Symbol>, Option<Loc>)>),
+}
+
+// source location of a form, as recorded by the reader
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loc {
+    pub file: Option<Rc<String>>,
+    pub line: usize,
+    pub col: usize,
 }
 
+pub type MalMap = PMap<MalVal, MalVal>;
 pub type MalArgs = Vec<MalVal>;
 pub type MalRet = Result<MalVal, MalErr>;
 
@@ -44,21 +82,23 @@ pub type MalRet = Result<MalVal, MalErr>;
 
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    let v: Vec<MalVal> = $seq;
+    List(Rc::new(v.into()),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    List(Rc::new(v.into()),Rc::new(Nil))
   }}
 }
 
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    let v: Vec<MalVal> = $seq;
+    Vector(Rc::new(v.into()),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    Vector(Rc::new(v.into()),Rc::new(Nil))
   }}
 }
 
@@ -68,13 +108,365 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+pub fn typed_error(kind: &'static str, s: &str) -> MalRet {
+    Err(ErrTyped(kind, s.to_string()))
+}
+
+type Calling = fn(MalErr, &MalVal, &MalVal) -> MalErr;
+
+thread_local! {
+    // what an error binding the args gets when a builtin (map, apply,
+    // swap!, ...) calls a mal function: stepA names the function
+    static CALLING: Cell<Calling> = Cell::new(|e, _, _| e);
+}
+
+pub fn set_calling(f: Calling) {
+    CALLING.with(|c| c.set(f));
+}
+
+// the parts of an ex-info map: its :type, :message and :data
+pub fn ex_parts(mv: &MalVal) -> Option<(&MalVal, &str, Option<&MalVal>)> {
+    let hm = match mv {
+        Hash(hm, _) => hm,
+        _ => return None,
+    };
+    match (hm.get(&keyword("type")), hm.get(&keyword("message"))) {
+        (Some(t @ Keyword(_)), Some(Str(msg))) => Some((t, msg, hm.get(&keyword("data")))),
+        _ => None,
+    }
+}
+
 pub fn format_error(e: MalErr) -> String {
-    match e {
-        ErrString(s) => s.clone(),
-        ErrMalVal(mv) => mv.pr_str(true),
+    match e.location() {
+        Some(loc) => format!("{} at {}", e.message(), loc),
+        None => e.message(),
+    }
+}
+
+impl MalErr {
+    // the value seen by a catch* handler (location is dropped)
+    pub fn value(&self) -> MalVal {
+        match self {
+            ErrString(s) | ErrTyped(_, s) => Str(s.to_string()),
+            ErrMalVal(mv) => mv.clone(),
+            ErrAt(e, _) | ErrTrace(e, _) => e.value(),
+        }
+    }
+
+    // the :type a typed catch* clause selects on
+    pub fn kind(&self) -> MalVal {
+        match self {
+            ErrString(_) => keyword("error"),
+            ErrTyped(kind, _) => keyword(kind),
+            ErrMalVal(mv) => ex_parts(mv).map_or(keyword("thrown"), |(t, _, _)| t.clone()),
+            ErrAt(e, _) | ErrTrace(e, _) => e.kind(),
+        }
+    }
+
+    // the value seen by a typed catch* clause: an ex-info map is thrown
+    // as itself, anything else is described by one
+    pub fn to_map(&self) -> MalVal {
+        let thrown = match self.root() {
+            ErrMalVal(mv) if ex_parts(mv).is_some() => return mv.clone(),
+            ErrMalVal(mv) => Some(mv),
+            _ => None,
+        };
+        let msg = match thrown {
+            Some(Str(s)) => s.clone(),
+            _ => self.message(),
+        };
+        let mut hm = MalMap::new();
+        hm.insert(keyword("type"), self.kind());
+        hm.insert(keyword("message"), Str(msg));
+        if let Some(mv) = thrown {
+            hm.insert(keyword("data"), mv.clone());
+        }
+        Hash(Rc::new(hm), Rc::new(Nil))
+    }
+
+    // the error itself, without location or trace
+    fn root(&self) -> &MalErr {
+        match self {
+            ErrAt(e, _) | ErrTrace(e, _) => e.root(),
+            e => e,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ErrString(s) | ErrTyped(_, s) => s.clone(),
+            ErrMalVal(mv) => match ex_parts(mv) {
+                Some((_, msg, None)) | Some((_, msg, Some(Nil))) => msg.to_string(),
+                Some((_, msg, Some(data))) => format!("{} {}", msg, data.pr_str(true)),
+                None => mv.pr_str(true),
+            },
+            ErrAt(e, _) | ErrTrace(e, _) => e.message(),
+        }
+    }
+
+    // where it was raised, as near as is known
+    fn location(&self) -> Option<&Loc> {
+        match self {
+            ErrAt(e, loc) => e.location().or(Some(loc)),
+            // a location kept by leaving_form is more specific
+            ErrTrace(e, frames) => e
+                .location()
+                .or_else(|| frames.iter().find_map(|(_, loc)| loc.as_ref())),
+            _ => None,
+        }
+    }
+
+    // the error as it passes out of a mal function: the location it has
+    // is the one in that function, and the caller adds its own
+    pub fn leaving(self, name: Option<Symbol>) -> MalErr {
+        let (e, loc) = match self {
+            ErrAt(e, loc) => (*e, Some(loc)),
+            e => (e, None),
+        };
+        match e {
+            ErrTrace(e, mut frames) => {
+                frames.push((name, loc));
+                ErrTrace(e, frames)
+            }
+            e => ErrTrace(Box::new(e), vec![(name, loc)]),
+        }
+    }
+
+    // the error as it passes out of a top-level form that a function
+    // evaluated, such as load-file: the frame is at the form, and a
+    // location in it is kept for the message unless frames inside say
+    // where the error came from
+    pub fn leaving_form(self, name: Option<Symbol>, form: &MalVal) -> MalErr {
+        let loc = match form {
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Loc::from_meta(meta),
+            _ => None,
+        };
+        let e = match self {
+            ErrAt(e, _) if matches!(*e, ErrTrace(..)) => *e,
+            e => e,
+        };
+        match e {
+            ErrTrace(e, mut frames) => {
+                frames.push((name, loc));
+                ErrTrace(e, frames)
+            }
+            e => ErrTrace(Box::new(e), vec![(name, loc)]),
+        }
+    }
+
+    pub fn trace(&self) -> &[(Option<Symbol>, Option<Loc>)] {
+        match self {
+            ErrAt(e, _) => e.trace(),
+            ErrTrace(_, frames) => frames,
+            _ => &[],
+        }
+    }
+
+    // attach the location from a form's metadata unless the error
+    // already has a (more specific) one
+    pub fn at(self, meta: &MalVal) -> MalErr {
+        match (self, Loc::from_meta(meta)) {
+            (e @ ErrAt(..), _) => e,
+            (e, Some(loc)) => ErrAt(Box::new(e), loc),
+            (e, None) => e,
+        }
+    }
+}
+
+impl Loc {
+    pub fn to_meta(&self) -> MalVal {
+        let mut kvs = vec![];
+        if let Some(ref f) = self.file {
+            kvs.push(keyword("file"));
+            kvs.push(Str(f.to_string()));
+        }
+        kvs.push(keyword("line"));
+        kvs.push(Int(self.line as i64));
+        kvs.push(keyword("column"));
+        kvs.push(Int(self.col as i64));
+        hash_map(kvs).unwrap_or(Nil)
+    }
+
+    pub fn from_meta(meta: &MalVal) -> Option<Loc> {
+        match meta {
+            Hash(hm, _) => match (hm.get(&keyword("line")), hm.get(&keyword("column"))) {
+                (Some(Int(line)), Some(Int(col))) => Some(Loc {
+                    file: match hm.get(&keyword("file")) {
+                        Some(Str(f)) => Some(Rc::new(f.to_string())),
+                        _ => None,
+                    },
+                    line: *line as usize,
+                    col: *col as usize,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}:{}", file, self.line, self.col),
+            None => write!(f, "line {}, column {}", self.line, self.col),
+        }
+    }
+}
+
+// integers that fit in an i64 are always Int, so every integer value
+// has exactly one representation
+pub fn integer(b: BigInt) -> MalVal {
+    match b.to_i64() {
+        Some(i) => Int(i),
+        None => Big(Rc::new(b)),
+    }
+}
+
+thread_local! {
+    static KEYWORDS: RefCell<FnvHashSet<Rc<str>>> = RefCell::new(FnvHashSet::default());
+    static SYMBOLS: RefCell<FnvHashMap<&'static str, u32>> = RefCell::new(FnvHashMap::default());
+    static REGEXES: RefCell<FnvHashMap<String, Rc<MalRegex>>> =
+        RefCell::new(FnvHashMap::default());
+}
+
+impl Symbol {
+    pub fn new(name: &str) -> Symbol {
+        SYMBOLS.with(|syms| {
+            let mut syms = syms.borrow_mut();
+            if let Some((name, id)) = syms.get_key_value(name) {
+                return Symbol { id: *id, name };
+            }
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            let id = syms.len() as u32;
+            syms.insert(name, id);
+            Symbol { id, name }
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.name == other
+    }
+}
+
+impl std::hash::Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl std::ops::Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.name
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub fn sym(name: &str) -> MalVal {
+    Sym(Symbol::new(name))
+}
+
+// keywords are interned, so two keywords are equal exactly when they
+// share the same name
+pub fn keyword(name: &str) -> MalVal {
+    KEYWORDS.with(|kws| {
+        let mut kws = kws.borrow_mut();
+        if let Some(k) = kws.get(name) {
+            return Keyword(k.clone());
+        }
+        let k: Rc<str> = Rc::from(name);
+        kws.insert(k.clone());
+        Keyword(k)
+    })
+}
+
+// A compiled regex, and the same pattern anchored at both ends, for
+// re-matches.
+#[derive(Debug)]
+pub struct MalRegex {
+    re: ::regex::Regex,
+    whole: ::regex::Regex,
+}
+
+impl MalRegex {
+    pub fn whole(&self) -> &::regex::Regex {
+        &self.whole
+    }
+}
+
+impl Deref for MalRegex {
+    type Target = ::regex::Regex;
+
+    fn deref(&self) -> &::regex::Regex {
+        &self.re
     }
 }
 
+fn compile(src: &str) -> Result<::regex::Regex, MalErr> {
+    match ::regex::Regex::new(src) {
+        Ok(re) => Ok(re),
+        Err(::regex::Error::Syntax(msg)) => {
+            let msg = msg
+                .lines()
+                .last()
+                .unwrap_or("")
+                .trim_start_matches("error: ");
+            Err(ErrTyped("regex-error", format!("invalid regex: {}", msg)))
+        }
+        Err(e) => Err(ErrTyped("regex-error", format!("invalid regex: {}", e))),
+    }
+}
+
+// regexes are compiled once per source; patterns made up at run time
+// could fill the cache without end, so it is cleared when it gets large
+pub fn regex(src: &str) -> MalRet {
+    REGEXES.with(|res| {
+        let mut res = res.borrow_mut();
+        if let Some(re) = res.get(src) {
+            return Ok(Regex(re.clone()));
+        }
+        // the group is closed on a line of its own, with (?x) so the
+        // newline is not matched, so that a # comment in a pattern using
+        // (?x) ends before it
+        let re = Rc::new(MalRegex {
+            re: compile(src)?,
+            whole: compile(&format!("\\A(?:{}(?x)\n)\\z", src))?,
+        });
+        if res.len() >= 1000 {
+            res.clear();
+        }
+        res.insert(src.to_string(), re.clone());
+        Ok(Regex(re))
+    })
+}
+
 pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
@@ -82,25 +474,28 @@ pub fn atom(mv: &MalVal) -> MalVal {
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
-            _ => error("invalid type for keyword"),
+            Str(s) => Ok(keyword(s)),
+            Keyword(_) => Ok(self.clone()),
+            _ => typed_error("type-error", "invalid type for keyword"),
         }
     }
 
     pub fn empty_q(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            Str(s) => Ok(Bool(s.is_empty())),
             Nil => Ok(Bool(true)),
-            _ => error("invalid type for empty?"),
+            _ => typed_error("type-error", "invalid type for empty?"),
         }
     }
 
     pub fn count(&self) -> MalRet {
         match self {
             List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            // in chars, as seq splits strings
+            Str(s) => Ok(Int(s.chars().count() as i64)),
             Nil => Ok(Int(0)),
-            _ => error("invalid type for count"),
+            _ => typed_error("type-error", "invalid type for count"),
         }
     }
 
@@ -112,28 +507,62 @@ impl MalVal {
                 ref ast,
                 ref env,
                 ref params,
+                ref meta,
                 ..
             } => {
-                let a = &**ast;
-                let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
+                let calling = |e| CALLING.with(Cell::get)(e, ast, meta);
+                let (p, a) = select_arity(params, ast, args.len()).map_err(calling)?;
+                let fn_env = env_bind(Some(env.clone()), p.clone(), args).map_err(calling)?;
                 Ok(eval(a.clone(), fn_env)?)
             }
-            _ => error("attempt to call non-function"),
+            // (:k m) and (:k m default) look the keyword up in a map
+            Keyword(_) if args.len() == 1 || args.len() == 2 => {
+                let found = match args[0] {
+                    Hash(ref hm, _) => hm.get(self).cloned(),
+                    _ => None,
+                };
+                Ok(found.unwrap_or_else(|| args.get(1).cloned().unwrap_or(Nil)))
+            }
+            Keyword(_) => typed_error(
+                "type-error",
+                "keyword lookup expects a map and an optional default",
+            ),
+            _ => typed_error("type-error", "attempt to call non-function"),
         }
     }
 
-    pub fn keyword_q(&self) -> bool {
+    pub fn to_bigint(&self) -> Option<BigInt> {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
+            Int(i) => Some(BigInt::from_i64(*i)),
+            Big(b) => Some((**b).clone()),
+            _ => None,
+        }
+    }
+
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Int(i) => Some(*i as f64),
+            Big(b) => Some(b.to_f64()),
+            Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    // whether the value can be used as a hash-map key: functions and
+    // atoms never compare equal, not even to themselves
+    pub fn hashable(&self) -> bool {
+        match self {
+            Func(..) | MalFunc { .. } | Atom(_) => false,
+            List(l, _) | Vector(l, _) => l.iter().all(|v| v.hashable()),
+            Hash(hm, _) => hm.values().all(|v| v.hashable()),
+            _ => true,
         }
     }
 
     pub fn deref(&self) -> MalRet {
         match self {
             Atom(a) => Ok(a.borrow().clone()),
-            _ => error("attempt to deref a non-Atom"),
+            _ => typed_error("type-error", "attempt to deref a non-Atom"),
         }
     }
 
@@ -143,7 +572,7 @@ impl MalVal {
                 *a.borrow_mut() = new.clone();
                 Ok(new.clone())
             }
-            _ => error("attempt to reset! a non-Atom"),
+            _ => typed_error("type-error", "attempt to reset! a non-Atom"),
         }
     }
 
@@ -156,7 +585,7 @@ impl MalVal {
                 *a.borrow_mut() = f.apply(fargs)?;
                 Ok(a.borrow().clone())
             }
-            _ => error("attempt to swap! a non-Atom"),
+            _ => typed_error("type-error", "attempt to swap! a non-Atom"),
         }
     }
 
@@ -165,7 +594,7 @@ impl MalVal {
             List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
             Func(_, meta) => Ok((&**meta).clone()),
             MalFunc { meta, .. } => Ok((&**meta).clone()),
-            _ => error("meta not supported by type"),
+            _ => typed_error("type-error", "meta not supported by type"),
         }
     }
 
@@ -178,7 +607,7 @@ impl MalVal {
             | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new((&*new_meta).clone());
             }
-            _ => return error("with-meta not supported by type"),
+            _ => return typed_error("type-error", "with-meta not supported by type"),
         };
         Ok(self.clone())
     }
@@ -190,8 +619,12 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (Big(ref a), Big(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => Rc::ptr_eq(a, b),
+            (Regex(ref a), Regex(ref b)) => a.as_str() == b.as_str(),
             (List(ref a, _), List(ref b, _))
             | (Vector(ref a, _), Vector(ref b, _))
             | (List(ref a, _), Vector(ref b, _))
@@ -203,38 +636,96 @@ impl PartialEq for MalVal {
     }
 }
 
+// Only hashable() values are ever used as keys, for which equality is
+// reflexive (apart from ##NaN, which, as in Clojure, can be stored but
+// never found again).
+impl Eq for MalVal {}
+
+// consistent with PartialEq: metadata is ignored, lists and vectors
+// hash alike and maps hash independently of iteration order
+impl std::hash::Hash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => 0u8.hash(state),
+            Bool(b) => {
+                1u8.hash(state);
+                b.hash(state)
+            }
+            Int(i) => {
+                2u8.hash(state);
+                i.hash(state)
+            }
+            Big(b) => {
+                3u8.hash(state);
+                b.hash(state)
+            }
+            Float(f) => {
+                4u8.hash(state);
+                // 0.0 == -0.0
+                (if *f == 0.0 { 0.0 } else { *f }).to_bits().hash(state)
+            }
+            Str(s) => {
+                5u8.hash(state);
+                s.hash(state)
+            }
+            Sym(s) | Local(_, _, s) => {
+                6u8.hash(state);
+                s.hash(state)
+            }
+            List(l, _) | Vector(l, _) => {
+                7u8.hash(state);
+                l.hash(state)
+            }
+            Hash(hm, _) => {
+                8u8.hash(state);
+                let mut sum = 0u64;
+                for (k, v) in hm.iter() {
+                    let mut h = FnvHasher::default();
+                    k.hash(&mut h);
+                    v.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                hm.len().hash(state);
+                sum.hash(state)
+            }
+            Keyword(k) => {
+                9u8.hash(state);
+                k.hash(state)
+            }
+            Regex(re) => {
+                11u8.hash(state);
+                re.as_str().hash(state)
+            }
+            Func(..) | MalFunc { .. } | Atom(_) => 10u8.hash(state),
+        }
+    }
+}
+
 pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
     Func(f, Rc::new(Nil))
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
-            }
-            _ => return error("key is not string"),
+        if !k.hashable() {
+            return error(&format!("unhashable map key: {}", k.pr_str(true)));
         }
+        hm.insert(k.clone(), v.clone());
     }
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        hm.remove(k);
     }
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+    let hm: MalMap = PMap::new();
     _assoc(hm, kvs)
 }
@@ -0,0 +1,878 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::bodies::BodyCache;
+use crate::destructure;
+use crate::env::{
+    arities, check_params, env_bind, env_get, env_get_slot, env_new, env_set, env_set_slot,
+    select_arity, Env,
+};
+use crate::limits::{self, Depth};
+use crate::pvec::PVec;
+use crate::resolve;
+use crate::trace;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Func, Hash, Keyword, List, Local, MalFunc, Nil, Sym, Vector};
+use crate::types::{
+    error, format_error, keyword, sym, typed_error, MalErr, MalMap, MalRet, MalVal, Symbol,
+};
+
+// An alternative to the evaluator in stepA_mal.rs: forms are compiled
+// to a flat list of ops over a value stack and run by a loop that keeps
+// its own call frames, so mal calls (tail or not) never recurse in Rust.
+//
+// Variables still live in env.rs frames, which closures capture. Code
+// is compiled when stepA's eval would analyze it: top-level forms as
+// read, and fn* bodies, resolved (see resolve.rs) when the closure is
+// created, the first time they are called.
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Const(usize),
+    Local(usize, usize),
+    Global(Symbol),
+    // pop a let* binding into a slot (resolved) or by name
+    SetLocal(usize, Symbol),
+    SetName(Symbol),
+    // def!/defmacro! the constant key to the popped value, leaving it
+    Def(usize),
+    DefMacro(usize),
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),
+    EnterScope,
+    LeaveScope,
+    Closure(usize),
+    // with the head of the constant call form on the stack: if it is a
+    // macro, replace it with the value of the expansion and jump
+    CheckMacro(usize, usize),
+    Call(usize),
+    TailCall(usize),
+    Return,
+    MakeVector(usize),
+    MakeHash(usize),
+    // install a handler at the target for errors until EndTry, which
+    // jumps over it; the handler is given the error, and tries each
+    // catch* clause in turn until one takes it with Catch, or Rethrows
+    Try(usize),
+    EndTry(usize),
+    // jump to the next clause unless the error is of the constant :type
+    CatchType(usize, usize),
+    // jump to the next clause unless the popped predicate takes the error
+    CatchIf(usize),
+    // bind the error (as its map, for a typed clause) in a new scope
+    Catch(Symbol, bool),
+    Rethrow,
+    Fail(usize),
+    // pop new values for the constant loop* bindings and jump back
+    Recur(usize, usize),
+    // run the constant loop* form once its macros are expanded
+    Loop(usize),
+    MacroExpand(usize),
+    Eval,
+}
+
+pub struct Chunk {
+    code: Vec<Op>,
+    consts: Vec<MalVal>,
+    // the params and body of each fn*
+    fns: Vec<(Rc<MalVal>, Rc<MalVal>)>,
+    // the metadata of the innermost list form each op belongs to
+    locs: Vec<usize>,
+    metas: Vec<MalVal>,
+    resolved: bool,
+}
+
+struct Compiler {
+    chunk: Chunk,
+    meta: usize,
+    // scopes entered by the enclosing forms, and the loop*s among them
+    // as (first op of the body, bindings, scopes outside it)
+    scopes: usize,
+    loops: Vec<(usize, usize, usize)>,
+    // the first misplaced recur, with where it is
+    err: Option<(String, usize)>,
+}
+
+// where a form sits: whether its value is returned from the frame, and
+// what a recur there would go back to
+#[derive(Clone, Copy)]
+struct Pos {
+    tail: bool,
+    recur: Recur,
+}
+
+#[derive(Clone, Copy)]
+enum Recur {
+    Outside,
+    NotTail,
+    // an index into Compiler::loops
+    Loop(usize),
+}
+
+impl Pos {
+    fn inner(self) -> Pos {
+        Pos {
+            tail: false,
+            recur: match self.recur {
+                Recur::Outside => Recur::Outside,
+                _ => Recur::NotTail,
+            },
+        }
+    }
+}
+
+const TOP: Pos = Pos {
+    tail: true,
+    recur: Recur::Outside,
+};
+
+fn compile(ast: &MalVal, resolved: bool) -> Result<Chunk, MalErr> {
+    let mut c = Compiler::new(resolved);
+    c.expr(ast, TOP);
+    c.finish()
+}
+
+impl Compiler {
+    fn new(resolved: bool) -> Compiler {
+        Compiler {
+            chunk: Chunk {
+                code: vec![],
+                consts: vec![],
+                fns: vec![],
+                locs: vec![],
+                metas: vec![Nil],
+                resolved,
+            },
+            meta: 0,
+            scopes: 0,
+            loops: vec![],
+            err: None,
+        }
+    }
+
+    fn finish(self) -> Result<Chunk, MalErr> {
+        match self.err {
+            Some((msg, meta)) => Err(ErrString(msg).at(&self.chunk.metas[meta])),
+            None => Ok(self.chunk),
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.locs.push(self.meta);
+        self.chunk.code.len() - 1
+    }
+
+    // point the jump at `at` to the next op
+    fn patch(&mut self, at: usize) {
+        let here = self.chunk.code.len();
+        self.chunk.code[at] = match self.chunk.code[at] {
+            Op::Jump(_) => Op::Jump(here),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(here),
+            Op::CheckMacro(k, _) => Op::CheckMacro(k, here),
+            Op::Try(_) => Op::Try(here),
+            Op::EndTry(_) => Op::EndTry(here),
+            Op::CatchType(k, _) => Op::CatchType(k, here),
+            Op::CatchIf(_) => Op::CatchIf(here),
+            op => op,
+        };
+    }
+
+    fn konst(&mut self, val: MalVal) -> usize {
+        self.chunk.consts.push(val);
+        self.chunk.consts.len() - 1
+    }
+
+    fn fail(&mut self, msg: &str) {
+        let k = self.konst(MalVal::Str(msg.to_string()));
+        self.emit(Op::Fail(k));
+    }
+
+    // an error in the form as a whole, reported before it is run
+    fn reject(&mut self, msg: String) {
+        if self.err.is_none() {
+            self.err = Some((msg, self.meta));
+        }
+    }
+
+    fn ret(&mut self, pos: Pos) {
+        if pos.tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    // a missing operand counts as nil
+    fn operand(&mut self, ast: Option<&MalVal>, pos: Pos) {
+        self.expr(ast.unwrap_or(&Nil), pos)
+    }
+
+    fn expr(&mut self, ast: &MalVal, pos: Pos) {
+        match ast {
+            Sym(s) => {
+                self.emit(Op::Global(*s));
+            }
+            Local(depth, slot, _) => {
+                self.emit(Op::Local(*depth, *slot));
+            }
+            List(l, m) if !l.is_empty() => {
+                let outer = self.meta;
+                if let Hash(..) = **m {
+                    self.chunk.metas.push((**m).clone());
+                    self.meta = self.chunk.metas.len() - 1;
+                }
+                self.list(l, ast, pos);
+                self.meta = outer;
+                return;
+            }
+            Vector(v, _) => {
+                for x in v.iter() {
+                    self.expr(x, pos.inner());
+                }
+                self.emit(Op::MakeVector(v.len()));
+            }
+            Hash(hm, _) => {
+                for (k, v) in hm.iter() {
+                    let k = self.konst(k.clone());
+                    self.emit(Op::Const(k));
+                    self.expr(v, pos.inner());
+                }
+                self.emit(Op::MakeHash(hm.len()));
+            }
+            _ => {
+                let k = self.konst(ast.clone());
+                self.emit(Op::Const(k));
+            }
+        }
+        self.ret(pos);
+    }
+
+    // the bindings of a let* or loop*, into a new scope; None if they
+    // are malformed, which is only an error when the form is run
+    fn bindings(&mut self, binds: Option<&MalVal>, pos: Pos) -> Option<PVec<MalVal>> {
+        let binds = match binds {
+            Some(List(binds, _)) | Some(Vector(binds, _)) => binds,
+            _ => {
+                self.fail("let* with non-List bindings");
+                return None;
+            }
+        };
+        self.emit(Op::EnterScope);
+        let mut targets = vec![];
+        for (b, e) in binds.iter().zip(binds.iter().skip(1)).step_by(2) {
+            match b {
+                Local(_, slot, s) => {
+                    self.expr(e, pos.inner());
+                    self.emit(Op::SetLocal(*slot, *s));
+                }
+                Sym(s) => {
+                    self.expr(e, pos.inner());
+                    self.emit(Op::SetName(*s));
+                }
+                _ => {
+                    self.fail("let* with non-Sym binding");
+                    return None;
+                }
+            }
+            targets.push(b.clone());
+        }
+        Some(targets.into())
+    }
+
+    // a let* whose body a recur jumps back to the start of
+    fn loop_form(&mut self, l: &PVec<MalVal>, pos: Pos) {
+        let targets = match self.bindings(l.get(1), pos) {
+            Some(targets) => targets,
+            None => return,
+        };
+        let k = self.konst(List(Rc::new(targets), Rc::new(Nil)));
+        self.loops.push((self.chunk.code.len(), k, self.scopes));
+        self.scopes += 1;
+        let body_pos = Pos {
+            tail: pos.tail,
+            recur: Recur::Loop(self.loops.len() - 1),
+        };
+        self.operand(l.get(2), body_pos);
+        self.scopes -= 1;
+        self.loops.pop();
+        if !pos.tail {
+            self.emit(Op::LeaveScope);
+        }
+    }
+
+    fn list(&mut self, l: &PVec<MalVal>, form: &MalVal, pos: Pos) {
+        match destructure::rewrite(form) {
+            Ok(Some(form)) => return self.expr(&form, pos),
+            Ok(None) => (),
+            Err(e) => self.reject(format_error(e)),
+        }
+        let a0 = match l[0] {
+            Sym(ref s) => s.name(),
+            _ => "",
+        };
+        let inner = pos.inner();
+        match a0 {
+            "def!" | "defmacro!" => {
+                self.operand(l.get(2), inner);
+                let k = self.konst(l.get(1).cloned().unwrap_or(Nil));
+                self.emit(if a0 == "def!" {
+                    Op::Def(k)
+                } else {
+                    Op::DefMacro(k)
+                });
+                self.ret(pos);
+            }
+            "let*" => {
+                if self.bindings(l.get(1), pos).is_none() {
+                    return;
+                }
+                self.scopes += 1;
+                self.operand(l.get(2), pos);
+                self.scopes -= 1;
+                if !pos.tail {
+                    self.emit(Op::LeaveScope);
+                }
+            }
+            "loop*" if self.chunk.resolved => self.loop_form(l, pos),
+            // macros are expanded first, so the recurs they make are seen
+            "loop*" => {
+                let k = self.konst(form.clone());
+                self.emit(Op::Loop(k));
+                self.ret(pos);
+            }
+            "recur" => {
+                let (start, k, scopes) = match pos.recur {
+                    Recur::Loop(i) => self.loops[i],
+                    Recur::NotTail => return self.reject("recur not in tail position".to_string()),
+                    Recur::Outside => return self.reject("recur outside of loop*".to_string()),
+                };
+                let n = match self.chunk.consts[k] {
+                    List(ref t, _) => t.len(),
+                    _ => 0,
+                };
+                if l.len() - 1 != n {
+                    return self.reject(format!("recur: expected {} args, got {}", n, l.len() - 1));
+                }
+                for x in l.iter().skip(1) {
+                    self.expr(x, inner);
+                }
+                // back out of the scopes opened inside the loop body
+                for _ in scopes + 1..self.scopes {
+                    self.emit(Op::LeaveScope);
+                }
+                self.emit(Op::Recur(k, start));
+            }
+            "quote" => {
+                let k = self.konst(l.get(1).cloned().unwrap_or(Nil));
+                self.emit(Op::Const(k));
+                self.ret(pos);
+            }
+            "quasiquoteexpand" => {
+                let k = self.konst(crate::quasiquote(l.get(1).unwrap_or(&Nil)));
+                self.emit(Op::Const(k));
+                self.ret(pos);
+            }
+            "quasiquote" => self.expr(&crate::quasiquote(l.get(1).unwrap_or(&Nil)), pos),
+            "macroexpand" => {
+                let k = self.konst(l.get(1).cloned().unwrap_or(Nil));
+                self.emit(Op::MacroExpand(k));
+                self.ret(pos);
+            }
+            "try*" if l.len() >= 3 => {
+                let last = l.len() - 1;
+                let finally = match resolve::try_clause(&l[last]) {
+                    resolve::Clause::Finally(forms) => {
+                        Some(list!([vec![sym("do")], forms].concat()))
+                    }
+                    _ => None,
+                };
+                let catches = last - 1 - finally.is_some() as usize;
+                let finally_at = finally.as_ref().map(|_| self.emit(Op::Try(0)));
+                if catches > 0 {
+                    let try_at = self.emit(Op::Try(0));
+                    self.expr(&l[1], inner);
+                    let end_at = self.emit(Op::EndTry(0));
+                    self.patch(try_at);
+                    let mut ends = vec![];
+                    for c in l.iter().skip(2).take(catches) {
+                        if let resolve::Clause::Catch(sel, exc, handler) = resolve::try_clause(c) {
+                            let next = match sel {
+                                None => None,
+                                Some(t @ Keyword(_)) => {
+                                    let k = self.konst(t.clone());
+                                    Some(self.emit(Op::CatchType(k, 0)))
+                                }
+                                Some(pred) => {
+                                    self.expr(pred, inner);
+                                    Some(self.emit(Op::CatchIf(0)))
+                                }
+                            };
+                            match exc {
+                                Sym(e) => {
+                                    self.emit(Op::Catch(*e, sel.is_some()));
+                                    self.scopes += 1;
+                                    self.expr(handler, inner);
+                                    self.scopes -= 1;
+                                    self.emit(Op::LeaveScope);
+                                    ends.push(self.emit(Op::Jump(0)));
+                                }
+                                _ => self.fail("env_bind binds not symbols"),
+                            }
+                            if let Some(next) = next {
+                                self.patch(next);
+                            }
+                        } else {
+                            self.fail("invalid catch block");
+                        }
+                    }
+                    self.emit(Op::Rethrow);
+                    self.patch(end_at);
+                    for at in ends {
+                        self.patch(at);
+                    }
+                } else {
+                    self.expr(&l[1], inner);
+                }
+                // the finally* forms run once the rest is done, both when
+                // it returns and, before the error goes on, when it fails
+                if let (Some(finally_at), Some(finally)) = (finally_at, finally) {
+                    let end_at = self.emit(Op::EndTry(0));
+                    self.patch(finally_at);
+                    self.expr(&finally, inner);
+                    self.emit(Op::Pop);
+                    self.emit(Op::Rethrow);
+                    self.patch(end_at);
+                    self.expr(&finally, inner);
+                    self.emit(Op::Pop);
+                }
+                self.ret(pos);
+            }
+            "try*" => {
+                self.operand(l.get(1), inner);
+                self.ret(pos);
+            }
+            "do" => {
+                let n = l.len() - 1;
+                for x in l.iter().skip(1).take(n.saturating_sub(1)) {
+                    self.expr(x, inner);
+                    self.emit(Op::Pop);
+                }
+                self.operand(l.get(n).filter(|_| n > 0), pos);
+            }
+            "if" => {
+                self.operand(l.get(1), inner);
+                let else_at = self.emit(Op::JumpIfFalse(0));
+                self.operand(l.get(2), pos);
+                let end_at = if pos.tail {
+                    None
+                } else {
+                    Some(self.emit(Op::Jump(0)))
+                };
+                self.patch(else_at);
+                self.operand(l.get(3), pos);
+                if let Some(end_at) = end_at {
+                    self.patch(end_at);
+                }
+            }
+            "fn*" => {
+                let (params, body) = match resolve::fn_arities(l) {
+                    Some(arities) => (Nil, arities),
+                    None => {
+                        let params = l.get(1).cloned().unwrap_or(Nil);
+                        let body = l.get(2).cloned().unwrap_or(Nil);
+                        (params.clone(), check_params(&params).map(|_| body))
+                    }
+                };
+                let body = match body {
+                    Ok(body) => body,
+                    Err(e) => {
+                        self.reject(format_error(e));
+                        Nil
+                    }
+                };
+                self.chunk.fns.push((Rc::new(params), Rc::new(body)));
+                self.emit(Op::Closure(self.chunk.fns.len() - 1));
+                self.ret(pos);
+            }
+            "eval" => {
+                self.operand(l.get(1), inner);
+                self.emit(Op::Eval);
+                self.ret(pos);
+            }
+            _ => {
+                self.expr(&l[0], inner);
+                let check_at = match l[0] {
+                    Sym(_) | Local(..) => {
+                        let k = self.konst(form.clone());
+                        Some(self.emit(Op::CheckMacro(k, 0)))
+                    }
+                    _ => None,
+                };
+                let (args_at, err) = (self.chunk.code.len(), self.err.is_some());
+                for x in l.iter().skip(1) {
+                    self.expr(x, inner);
+                }
+                // the arguments of a macro call need not make sense as
+                // code, so that can only be told once the head is known
+                if check_at.is_some() && !err && self.err.is_some() {
+                    let (msg, meta) = self.err.take().unwrap();
+                    self.chunk.code.truncate(args_at);
+                    self.chunk.locs.truncate(args_at);
+                    self.fail(&msg);
+                    *self.chunk.locs.last_mut().unwrap() = meta;
+                } else {
+                    self.emit(if pos.tail {
+                        Op::TailCall(l.len() - 1)
+                    } else {
+                        Op::Call(l.len() - 1)
+                    });
+                }
+                if let Some(check_at) = check_at {
+                    self.patch(check_at);
+                }
+                // only reached after a macro call
+                self.ret(pos);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static BODIES: RefCell<BodyCache<Rc<Chunk>>> = RefCell::new(BodyCache::new());
+}
+
+fn body_chunk(body: &MalVal) -> Result<Rc<Chunk>, MalErr> {
+    if let Some(chunk) = BODIES.with(|b| b.borrow().get(body)) {
+        return Ok(chunk);
+    }
+    let chunk = Rc::new(compile(body, true)?);
+    BODIES.with(|b| b.borrow_mut().insert(body, chunk.clone()));
+    Ok(chunk)
+}
+
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    run(Rc::new(compile(&ast, false)?), env)
+}
+
+// the `eval` of the MalFuncs the VM creates, used when they are called
+// from outside it (by core functions such as map or swap!)
+pub fn eval_body(ast: MalVal, env: Env) -> MalRet {
+    limits::tick()?;
+    run(body_chunk(&ast)?, env).map_err(|e| trace::leave(e, &ast, &Nil))
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    env: Env,
+    // the stack height when it was called
+    base: usize,
+    // the body and metadata of the mal function, for traces
+    func: Option<(Rc<MalVal>, Rc<MalVal>)>,
+}
+
+impl Frame {
+    // the metadata of the form the last op run belongs to
+    fn meta(&self) -> &MalVal {
+        &self.chunk.metas[self.chunk.locs[self.ip - 1]]
+    }
+
+    fn leave(&self, e: MalErr) -> MalErr {
+        match self.func {
+            Some((ref ast, ref meta)) => trace::leave(e, ast, meta),
+            None => e,
+        }
+    }
+}
+
+struct Handler {
+    frames: usize,
+    stack: usize,
+    errors: usize,
+    env: Env,
+    target: usize,
+}
+
+struct Vm {
+    stack: Vec<MalVal>,
+    // the callers of the running frame
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    // the errors being handled, innermost last
+    errors: Vec<MalErr>,
+}
+
+fn run(chunk: Rc<Chunk>, env: Env) -> MalRet {
+    let _depth = Depth::enter()?;
+    let mut vm = Vm {
+        stack: vec![],
+        frames: vec![],
+        handlers: vec![],
+        errors: vec![],
+    };
+    let mut fr = Frame {
+        chunk,
+        ip: 0,
+        env,
+        base: 0,
+        func: None,
+    };
+    loop {
+        let mut e = match vm.exec(&mut fr) {
+            Ok(v) => return Ok(v),
+            Err(e) => e.at(fr.meta()),
+        };
+        let h = vm.handlers.pop();
+        while vm.frames.len() > h.as_ref().map_or(0, |h| h.frames) {
+            e = fr.leave(e);
+            fr = vm.frames.pop().unwrap();
+            e = e.at(fr.meta());
+        }
+        let h = match h {
+            Some(h) => h,
+            None => return Err(fr.leave(e)),
+        };
+        vm.stack.truncate(h.stack);
+        vm.errors.truncate(h.errors);
+        vm.errors.push(e);
+        fr.env = h.env;
+        fr.ip = h.target;
+    }
+}
+
+impl Vm {
+    fn pop(&mut self) -> MalVal {
+        self.stack.pop().unwrap()
+    }
+
+    // runs until the outermost frame returns or an op fails, leaving
+    // `fr` at the failing op
+    fn exec(&mut self, fr: &mut Frame) -> MalRet {
+        loop {
+            let op = fr.chunk.code[fr.ip];
+            fr.ip += 1;
+            match op {
+                Op::Const(k) => self.stack.push(fr.chunk.consts[k].clone()),
+                Op::Local(depth, slot) => self.stack.push(env_get_slot(&fr.env, depth, slot)),
+                Op::Global(s) => self.stack.push(env_get(&fr.env, &Sym(s))?),
+                Op::SetLocal(slot, s) => {
+                    let v = self.pop();
+                    env_set_slot(&fr.env, slot, s, v);
+                }
+                Op::SetName(s) => {
+                    let v = self.pop();
+                    env_set(&fr.env, Sym(s), v)?;
+                }
+                Op::Def(k) => {
+                    let v = self.pop();
+                    trace::named(&fr.chunk.consts[k], &v);
+                    let v = env_set(&fr.env, fr.chunk.consts[k].clone(), v)?;
+                    self.stack.push(v);
+                }
+                Op::DefMacro(k) => match self.pop() {
+                    MalFunc {
+                        eval,
+                        ast,
+                        env,
+                        params,
+                        ..
+                    } => {
+                        let mac = MalFunc {
+                            eval,
+                            ast,
+                            env,
+                            params,
+                            is_macro: true,
+                            meta: Rc::new(Nil),
+                        };
+                        trace::named(&fr.chunk.consts[k], &mac);
+                        let v = env_set(&fr.env, fr.chunk.consts[k].clone(), mac)?;
+                        self.stack.push(v);
+                    }
+                    _ => return error("set_macro on non-function"),
+                },
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Jump(to) => fr.ip = to,
+                Op::JumpIfFalse(to) => {
+                    if let Bool(false) | Nil = self.pop() {
+                        fr.ip = to;
+                    }
+                }
+                Op::EnterScope => fr.env = env_new(Some(fr.env.clone())),
+                Op::LeaveScope => fr.env = fr.env.outer.clone().unwrap(),
+                Op::Closure(i) => {
+                    let (ref params, ref body) = fr.chunk.fns[i];
+                    let body = if fr.chunk.resolved {
+                        body.clone()
+                    } else {
+                        resolve::resolve_fn(params, body, &fr.env)
+                    };
+                    // compiled now, so that errors in it show up here
+                    for (_, body) in arities(params, &body) {
+                        body_chunk(body)?;
+                    }
+                    self.stack.push(MalFunc {
+                        eval: eval_body,
+                        ast: body,
+                        env: fr.env.clone(),
+                        params: params.clone(),
+                        is_macro: false,
+                        meta: Rc::new(Nil),
+                    });
+                }
+                Op::CheckMacro(k, to) => {
+                    if let Some(MalFunc { is_macro: true, .. }) = self.stack.last() {
+                        let mac = self.pop();
+                        let args = match fr.chunk.consts[k] {
+                            List(ref l, _) => l.iter().skip(1).map(resolve::unresolve).collect(),
+                            _ => vec![],
+                        };
+                        let v = eval(mac.apply(args)?, fr.env.clone())?;
+                        self.stack.push(v);
+                        fr.ip = to;
+                    }
+                }
+                Op::Call(n) | Op::TailCall(n) => {
+                    limits::tick()?;
+                    let args = self.stack.split_off(self.stack.len() - n);
+                    match self.pop() {
+                        MalFunc {
+                            ast,
+                            env,
+                            params,
+                            meta,
+                            ..
+                        } => {
+                            let (params, body) = select_arity(&params, &ast, args.len())
+                                .map_err(|e| trace::calling(e, &ast, &meta))?;
+                            let env = env_bind(Some(env), params.clone(), args)
+                                .map_err(|e| trace::calling(e, &ast, &meta))?;
+                            let chunk = body_chunk(body)?;
+                            if let Op::TailCall(_) = op {
+                                self.stack.truncate(fr.base);
+                                fr.chunk = chunk;
+                                fr.ip = 0;
+                                fr.env = env;
+                                fr.func = Some((ast, meta));
+                            } else {
+                                limits::check_depth(self.frames.len())?;
+                                let callee = Frame {
+                                    chunk,
+                                    ip: 0,
+                                    env,
+                                    base: self.stack.len(),
+                                    func: Some((ast, meta)),
+                                };
+                                self.frames.push(std::mem::replace(fr, callee));
+                            }
+                        }
+                        f @ Func(..) | f @ Keyword(_) => self.stack.push(f.apply(args)?),
+                        _ => return typed_error("type-error", "attempt to call non-function"),
+                    }
+                }
+                Op::Return => {
+                    let v = self.pop();
+                    self.stack.truncate(fr.base);
+                    match self.frames.pop() {
+                        Some(caller) => {
+                            *fr = caller;
+                            self.stack.push(v);
+                        }
+                        None => return Ok(v),
+                    }
+                }
+                Op::MakeVector(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(vector!(items));
+                }
+                Op::MakeHash(n) => {
+                    let items = self.stack.split_off(self.stack.len() - 2 * n);
+                    let mut hm = MalMap::new();
+                    let mut it = items.into_iter();
+                    while let (Some(k), Some(v)) = (it.next(), it.next()) {
+                        hm.insert(k, v);
+                    }
+                    self.stack.push(Hash(Rc::new(hm), Rc::new(Nil)));
+                }
+                Op::Try(to) => self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    stack: self.stack.len(),
+                    errors: self.errors.len(),
+                    env: fr.env.clone(),
+                    target: to,
+                }),
+                Op::EndTry(to) => {
+                    self.handlers.pop();
+                    fr.ip = to;
+                }
+                Op::CatchType(k, to) => {
+                    let t = &fr.chunk.consts[k];
+                    let e = self.errors.last().unwrap();
+                    if *t != keyword("default") && *t != e.kind() {
+                        fr.ip = to;
+                    }
+                }
+                Op::CatchIf(to) => {
+                    let pred = self.pop();
+                    let e = self.errors.last().unwrap().to_map();
+                    if let Bool(false) | Nil = pred.apply(vec![e])? {
+                        fr.ip = to;
+                    }
+                }
+                Op::Catch(e, typed) => {
+                    let err = self.errors.pop().unwrap();
+                    trace::caught(&err);
+                    let exc = if typed { err.to_map() } else { err.value() };
+                    fr.env = env_new(Some(fr.env.clone()));
+                    env_set_slot(&fr.env, 0, e, exc);
+                }
+                Op::Rethrow => return Err(self.errors.pop().unwrap()),
+                Op::Recur(k, to) => {
+                    limits::tick()?;
+                    let targets = match fr.chunk.consts[k] {
+                        List(ref t, _) => t.clone(),
+                        _ => unreachable!(),
+                    };
+                    let vals = self.stack.split_off(self.stack.len() - targets.len());
+                    // the frame is reused unless a closure kept it
+                    if Rc::strong_count(&fr.env) > 1 {
+                        fr.env = env_new(fr.env.outer.clone());
+                    }
+                    for (b, v) in targets.iter().zip(vals) {
+                        match b {
+                            Local(_, slot, s) => env_set_slot(&fr.env, *slot, *s, v),
+                            _ => {
+                                env_set(&fr.env, b.clone(), v)?;
+                            }
+                        }
+                    }
+                    fr.ip = to;
+                }
+                Op::Loop(k) => {
+                    let ast = resolve::expand_macros(&fr.chunk.consts[k], &fr.env);
+                    let mut c = Compiler::new(false);
+                    match ast {
+                        List(ref l, _) => c.loop_form(l, TOP),
+                        _ => unreachable!(),
+                    }
+                    self.stack.push(run(Rc::new(c.finish()?), fr.env.clone())?);
+                }
+                Op::Fail(k) => return error(&fr.chunk.consts[k].pr_str(false)),
+                Op::MacroExpand(k) => {
+                    let v = crate::macroexpand(fr.chunk.consts[k].clone(), &fr.env).1?;
+                    self.stack.push(v);
+                }
+                Op::Eval => {
+                    let ast = self.pop();
+                    let mut env = fr.env.clone();
+                    while let Some(ref e) = env.clone().outer {
+                        env = e.clone();
+                    }
+                    self.stack.push(eval(ast, env)?);
+                }
+            }
+        }
+    }
+}